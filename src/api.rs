@@ -1,24 +1,182 @@
-use std::{collections::HashSet, net::SocketAddr, sync::Arc};
+use std::{
+    collections::{BTreeSet, HashSet},
+    fmt,
+    future::Future,
+    net::SocketAddr,
+    str::FromStr,
+    sync::{Arc, LazyLock, Mutex as StdMutex},
+    time::Duration,
+};
 
 use actix_web::{
+    delete,
     dev::Server,
     get,
+    http::{
+        header::{
+            ContentType, HeaderValue, AUTHORIZATION, CACHE_CONTROL, ETAG, IF_NONE_MATCH,
+            WWW_AUTHENTICATE,
+        },
+        StatusCode,
+    },
     middleware::Logger,
-    put,
-    web::{Data, Path},
-    App, HttpResponse, HttpServer, Responder,
+    post, put,
+    web::{to, Data, Path, Query},
+    App, HttpRequest, HttpResponse, HttpServer, Responder,
+};
+use anyhow::{anyhow, Context, Result};
+use futures_util::future::join_all;
+use hickory_server::proto::rr::Name;
+use log::{debug, error, info, warn};
+use rand::{thread_rng, Rng};
+use reqwest::{Client, Method, RequestBuilder, Response, Url};
+use serde::{Deserialize, Serialize};
+use serde_json::{from_str, to_string, to_value};
+use tokio::{
+    sync::Mutex,
+    time::{sleep, timeout},
 };
-use anyhow::{Context, Result};
-use log::{error, info};
-use reqwest::Url;
-use serde_json::{from_str, to_string};
-use tokio::sync::Mutex;
 
-use crate::registry::Registry;
+use crate::{
+    conflict::{conflicts, Conflict, ConflictPolicy},
+    constants::{REGISTRY_TLS_CA_ENV, REGISTRY_TOKEN_ENV},
+    env::Env,
+    gossip::{admit, forget, merge, peers, remember, Peer},
+    metrics::{encode, record_registry_services, REGISTRY_REQUESTS},
+    registry::{parse_update, Registry, Update, UpdateError},
+    tls::{configure_client, on_connect, server_config, PeerCertificate},
+    watch::{
+        publish_changes, publish_removed, subscribe, subscribe_changes, wait_for_change, Visibility,
+    },
+};
 
 struct State {
     pub self_registry: Arc<Mutex<Registry>>,
     pub registries: Arc<Mutex<Vec<Registry>>>,
+    pub token: Option<String>,
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+fn check_access(
+    certificate: Option<&PeerCertificate>,
+    authorization: Option<&str>,
+    token: Option<&str>,
+    hostname: &str,
+) -> Result<(), StatusCode> {
+    if certificate.is_some_and(|cert| {
+        Name::from_str(hostname).is_ok_and(|hostname| cert.is_valid_for(&hostname))
+    }) {
+        return Ok(());
+    }
+
+    let authorized = match token {
+        Some(token) => authorization
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|value| constant_time_eq(value.as_bytes(), token.as_bytes())),
+        None => certificate.is_none(),
+    };
+
+    match (authorized, certificate) {
+        (true, _) => Ok(()),
+        (false, Some(_)) => Err(StatusCode::FORBIDDEN),
+        (false, None) => Err(StatusCode::UNAUTHORIZED),
+    }
+}
+
+fn request_access(
+    request: &HttpRequest,
+    token: Option<&str>,
+    hostname: &str,
+) -> Result<(), StatusCode> {
+    check_access(
+        request.conn_data::<PeerCertificate>(),
+        request
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok()),
+        token,
+        hostname,
+    )
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ApiError {
+    status: u16,
+    error: String,
+}
+
+impl ApiError {
+    fn response(status: StatusCode, error: impl Into<String>) -> HttpResponse {
+        HttpResponse::build(status).json(Self {
+            status: status.as_u16(),
+            error: error.into(),
+        })
+    }
+}
+
+fn unauthorized() -> HttpResponse {
+    let mut response = ApiError::response(StatusCode::UNAUTHORIZED, "Missing or invalid token.");
+    response
+        .headers_mut()
+        .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+
+    response
+}
+
+fn check_authorization(
+    request: &HttpRequest,
+    data: &State,
+    hostname: &str,
+) -> Result<(), HttpResponse> {
+    let Err(status) = request_access(request, data.token.as_deref(), hostname) else {
+        return Ok(());
+    };
+
+    let peer = request
+        .peer_addr()
+        .map_or("unknown".to_string(), |addr| addr.to_string());
+    if status == StatusCode::FORBIDDEN {
+        warn!(
+            "Rejected `{} {}` from `{}` with a peer certificate not issued for `{}`.",
+            request.method(),
+            request.path(),
+            peer,
+            hostname
+        );
+
+        return Err(ApiError::response(
+            status,
+            format!("Peer certificate is not valid for `{}`.", hostname),
+        ));
+    }
+
+    warn!(
+        "Rejected unauthenticated `{} {}` from `{}`.",
+        request.method(),
+        request.path(),
+        peer
+    );
+
+    Err(unauthorized())
+}
+
+async fn check_admin_authorization(
+    request: &HttpRequest,
+    data: &State,
+) -> Result<(), HttpResponse> {
+    let self_hostname = data.self_registry.lock().await.hostname().to_string();
+
+    check_authorization(request, data, &self_hostname)
+}
+
+async fn not_found(request: HttpRequest) -> impl Responder {
+    ApiError::response(
+        StatusCode::NOT_FOUND,
+        format!("No route for `{} {}`.", request.method(), request.path()),
+    )
 }
 
 #[get("/health")]
@@ -26,34 +184,415 @@ async fn health() -> impl Responder {
     HttpResponse::Ok().body("OK")
 }
 
+const MAX_WAIT: Duration = Duration::from_secs(300);
+const SERVICES_VERSION: u32 = 2;
+
+#[derive(Deserialize)]
+struct ServicesQuery {
+    version: Option<u32>,
+    epoch: Option<u64>,
+    since: Option<u64>,
+    wait: Option<String>,
+}
+
+fn parse_wait(wait: &str) -> Option<Duration> {
+    let wait = wait.trim();
+    let (value, unit) = match wait.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => wait.split_at(index),
+        None => (wait, "s"),
+    };
+    let value: u64 = value.parse().ok()?;
+
+    let wait = match unit {
+        "ms" => Duration::from_millis(value),
+        "s" => Duration::from_secs(value),
+        "m" => Duration::from_secs(value.checked_mul(60)?),
+        _ => return None,
+    };
+
+    Some(wait.min(MAX_WAIT))
+}
+
+fn is_none_match(request: &HttpRequest, etag: &str) -> bool {
+    request
+        .headers()
+        .get(IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| {
+            value
+                .split(',')
+                .map(str::trim)
+                .any(|candidate| candidate == "*" || candidate.trim_start_matches("W/") == etag)
+        })
+}
+
+fn stable_hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+// without `version=2`, services are listed by name only, as registries that haven't been upgraded expect
+fn services_representation(registry: &Registry, query: &ServicesQuery) -> (String, String) {
+    let body = match (query.version, query.since) {
+        (Some(SERVICES_VERSION), Some(since)) => {
+            to_value(registry.update_since(query.epoch.unwrap_or(registry.epoch()), since))
+        }
+        (Some(SERVICES_VERSION), None) => to_value(registry.public_services()),
+        _ => to_value(registry.public_services().keys().collect::<BTreeSet<_>>()),
+    }
+    .unwrap()
+    .to_string();
+
+    let etag = format!(
+        "\"{:x}-{:x}-{:x}\"",
+        registry.epoch(),
+        registry.generation(),
+        stable_hash(body.as_bytes())
+    );
+
+    (etag, body)
+}
+
+async fn find_services_registry(data: &State, hostname: Option<&str>) -> Option<Registry> {
+    match hostname {
+        None => Some(data.self_registry.lock().await.clone()),
+        Some(hostname) => data
+            .registries
+            .lock()
+            .await
+            .iter()
+            .find(|registry| registry.hostname().to_string() == hostname)
+            .cloned(),
+    }
+}
+
+async fn services_response(
+    request: &HttpRequest,
+    query: &ServicesQuery,
+    data: &State,
+    hostname: Option<&str>,
+) -> HttpResponse {
+    let wait = match query.wait.as_deref().map(parse_wait) {
+        Some(None) => {
+            return ApiError::response(
+                StatusCode::BAD_REQUEST,
+                "Invalid wait duration, expected e.g. `30s`.",
+            )
+        }
+        Some(wait) => wait,
+        None => None,
+    };
+    match query.version {
+        None | Some(1) if query.since.is_some() => {
+            return ApiError::response(
+                StatusCode::BAD_REQUEST,
+                format!("`since` requires `version={}`.", SERVICES_VERSION),
+            )
+        }
+        None | Some(1) | Some(SERVICES_VERSION) => {}
+        Some(version) => {
+            return ApiError::response(
+                StatusCode::BAD_REQUEST,
+                format!("Unsupported services version `{}`.", version),
+            )
+        }
+    }
+
+    let not_found = || {
+        ApiError::response(
+            StatusCode::NOT_FOUND,
+            format!("Registry `{}` is not known.", hostname.unwrap_or("self")),
+        )
+    };
+
+    let mut changes = subscribe_changes();
+    let Some(registry) = find_services_registry(data, hostname).await else {
+        return not_found();
+    };
+
+    let (mut etag, mut body) = services_representation(&registry, query);
+    if let Some(wait) = wait {
+        if is_none_match(request, &etag) {
+            let registry_hostname = registry.hostname().to_string();
+            let _ = timeout(
+                wait,
+                wait_for_change(&mut changes, &registry_hostname, Visibility::Public),
+            )
+            .await;
+
+            let Some(registry) = find_services_registry(data, hostname).await else {
+                return not_found();
+            };
+            (etag, body) = services_representation(&registry, query);
+        }
+    }
+
+    if is_none_match(request, &etag) {
+        return HttpResponse::NotModified()
+            .insert_header((ETAG, etag))
+            .finish();
+    }
+
+    HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .insert_header((ETAG, etag))
+        .body(body)
+}
+
 #[get("/api/self/services")]
-async fn get_self_services(data: Data<State>) -> impl Responder {
-    let self_registry = &*data.self_registry.lock().await;
+async fn get_self_services(
+    request: HttpRequest,
+    query: Query<ServicesQuery>,
+    data: Data<State>,
+) -> impl Responder {
+    services_response(&request, &query, &data, None).await
+}
+
+#[get("/metrics")]
+async fn metrics(data: Data<State>) -> impl Responder {
+    let self_registry = data.self_registry.lock().await.clone();
+    let registries = data.registries.lock().await.clone();
+    record_registry_services(&self_registry, &registries);
+
+    match encode() {
+        Ok(metrics) => HttpResponse::Ok()
+            .content_type("text/plain; version=0.0.4")
+            .body(metrics),
+        Err(err) => {
+            error!("{}\nError: {}", err, err.root_cause());
+            ApiError::response(StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
+        }
+    }
+}
 
-    HttpResponse::Ok().json(self_registry.public_services())
+#[derive(Serialize)]
+struct RegistriesResponse {
+    #[serde(rename = "self")]
+    self_registry: Registry,
+    registries: Vec<Registry>,
 }
 
-#[get("/api/{registry_hostname}/services")]
-async fn get_registry_services(path: Path<String>, data: Data<State>) -> impl Responder {
-    let registries = data.registries.lock().await;
-    let registry_hostname = path.into_inner();
+#[get("/api/registries")]
+async fn get_registries(request: HttpRequest, data: Data<State>) -> impl Responder {
+    let mut self_registry = data.self_registry.lock().await.clone();
+    let mut registries = data.registries.lock().await.clone();
+    let self_hostname = self_registry.hostname().to_string();
+    if request_access(&request, data.token.as_deref(), &self_hostname).is_err() {
+        for registry in [&mut self_registry].into_iter().chain(&mut registries) {
+            registry.clear_service_addresses();
+        }
+    }
 
-    let registry = registries
-        .iter()
-        .find(|registry| registry.hostname().to_string() == registry_hostname);
-    if let Some(registry) = registry {
-        HttpResponse::Ok().json(registry.public_services())
-    } else {
-        HttpResponse::Ok().body("null")
+    HttpResponse::Ok().json(RegistriesResponse {
+        self_registry,
+        registries,
+    })
+}
+
+#[derive(Serialize)]
+struct ConflictsResponse {
+    policy: ConflictPolicy,
+    conflicts: Vec<Conflict>,
+}
+
+#[get("/api/conflicts")]
+async fn get_conflicts(data: Data<State>) -> impl Responder {
+    let self_registry = data.self_registry.lock().await.clone();
+    let registries = data.registries.lock().await.clone();
+
+    HttpResponse::Ok().json(ConflictsResponse {
+        policy: Env::service_conflict_policy(),
+        conflicts: conflicts(&self_registry, &registries, &Env::service_priority()),
+    })
+}
+
+#[get("/api/peers")]
+async fn get_peers(data: Data<State>) -> impl Responder {
+    HttpResponse::Ok().json(peers(&data.registries.lock().await))
+}
+
+#[derive(Serialize, Deserialize)]
+struct Introduction {
+    url: String,
+}
+
+#[put("/api/peers")]
+async fn put_peers(request: HttpRequest, body: String, data: Data<State>) -> impl Responder {
+    let introduction: Introduction = match from_str(&body) {
+        Ok(introduction) => introduction,
+        Err(err) => {
+            return ApiError::response(
+                StatusCode::BAD_REQUEST,
+                format!("Invalid introduction: {}", err),
+            )
+        }
+    };
+    let mut registry: Registry = match introduction.url.parse() {
+        Ok(registry) => registry,
+        Err(err) => {
+            return ApiError::response(StatusCode::BAD_REQUEST, format!("Invalid URL: {}", err))
+        }
+    };
+    if let Err(response) = check_authorization(&request, &data, &registry.hostname().to_string()) {
+        return response;
+    }
+
+    let self_hostname = data.self_registry.lock().await.hostname().clone();
+    let mut registries = data.registries.lock().await;
+    let peers = peers(&registries);
+
+    if Env::registry_token().is_some() && registry.url().scheme() != "https" {
+        debug!(
+            "Not admitting registry `{}`, its URL `{}` does not use `https`.",
+            registry.hostname(),
+            registry.url()
+        );
+    } else if Env::registry_gossip_hops().is_some() {
+        remember(registry.hostname());
+        registry.set_hops(1);
+        if admit(
+            &self_hostname,
+            &mut registries,
+            registry.clone(),
+            Env::registry_max_peers(),
+        ) {
+            info!(
+                "Registry `{}` introduced itself at `{}`.",
+                registry.hostname(),
+                registry.url()
+            );
+        }
     }
+
+    HttpResponse::Ok().json(peers)
+}
+
+#[get("/api/watch")]
+async fn watch(data: Data<State>) -> impl Responder {
+    let changes = subscribe_changes();
+    let mut registries = vec![data.self_registry.lock().await.clone()];
+    registries.extend(data.registries.lock().await.iter().cloned());
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((CACHE_CONTROL, "no-cache"))
+        .streaming(subscribe(changes, registries))
+}
+
+#[get("/api/{registry_hostname}/services")]
+async fn get_registry_services(
+    request: HttpRequest,
+    path: Path<String>,
+    query: Query<ServicesQuery>,
+    data: Data<State>,
+) -> impl Responder {
+    services_response(&request, &query, &data, Some(path.as_str())).await
 }
 
 #[put("/api/{registry_hostname}/services")]
 async fn put_registry_services(
+    request: HttpRequest,
     path: Path<String>,
     services: String,
     data: Data<State>,
 ) -> impl Responder {
+    if let Err(response) = check_authorization(&request, &data, path.as_str()) {
+        return response;
+    }
+
+    let registry_hostname = path.into_inner();
+    if data.self_registry.lock().await.hostname().to_string() == registry_hostname {
+        return ApiError::response(
+            StatusCode::CONFLICT,
+            format!("Registry `{}` is the self registry.", registry_hostname),
+        );
+    }
+
+    let update = match parse_update(&services) {
+        Ok(update) => update,
+        Err(err) => {
+            return ApiError::response(
+                StatusCode::BAD_REQUEST,
+                format!("Invalid services: {}", err),
+            )
+        }
+    };
+
+    let mut registries = data.registries.lock().await;
+    let Some(registry) = registries
+        .iter_mut()
+        .find(|registry| registry.hostname().to_string() == registry_hostname)
+    else {
+        return ApiError::response(
+            StatusCode::NOT_FOUND,
+            format!("Registry `{}` is not known.", registry_hostname),
+        );
+    };
+
+    let previous = registry.clone();
+    if let Err(err) = registry.apply_update(update) {
+        warn!(
+            "Rejected update of registry `{}`.\nError: {}",
+            registry_hostname, err
+        );
+        return ApiError::response(StatusCode::CONFLICT, err.to_string());
+    }
+    if !previous.is_healthy() {
+        info!("Registry `{}` recovered.", registry.hostname());
+    }
+    publish_changes(&previous, registry);
+
+    HttpResponse::NoContent().finish()
+}
+
+#[delete("/api/{registry_hostname}")]
+async fn delete_registry(
+    request: HttpRequest,
+    path: Path<String>,
+    data: Data<State>,
+) -> impl Responder {
+    if let Err(response) = check_admin_authorization(&request, &data).await {
+        return response;
+    }
+
+    let registry_hostname = path.into_inner();
+    if data.self_registry.lock().await.hostname().to_string() == registry_hostname {
+        return ApiError::response(
+            StatusCode::CONFLICT,
+            format!("Registry `{}` is the self registry.", registry_hostname),
+        );
+    }
+
+    let mut registries = data.registries.lock().await;
+    let Some(index) = registries
+        .iter()
+        .position(|registry| registry.hostname().to_string() == registry_hostname)
+    else {
+        return ApiError::response(
+            StatusCode::NOT_FOUND,
+            format!("Registry `{}` is not known.", registry_hostname),
+        );
+    };
+
+    let registry = registries.remove(index);
+    forget(registry.hostname());
+    publish_removed(&registry);
+    info!("Removed registry `{}`.", registry_hostname);
+
+    HttpResponse::NoContent().finish()
+}
+
+#[delete("/api/{registry_hostname}/services")]
+async fn delete_registry_services(
+    request: HttpRequest,
+    path: Path<String>,
+    data: Data<State>,
+) -> impl Responder {
+    if let Err(response) = check_admin_authorization(&request, &data).await {
+        return response;
+    }
+
     let mut registries = data.registries.lock().await;
     let registry_hostname = path.into_inner();
 
@@ -61,22 +600,69 @@ async fn put_registry_services(
         .iter_mut()
         .find(|registry| registry.hostname().to_string() == registry_hostname);
     if let Some(registry) = registry {
-        match from_str::<HashSet<String>>(&services) {
-            Ok(services) => {
-                registry.flush_public_services(services);
+        let previous = registry.clone();
+        registry.clear_public_services();
+        registry.reset_generation();
+        publish_changes(&previous, registry);
+        info!(
+            "Cleared public services of registry `{}`.",
+            registry_hostname
+        );
 
-                HttpResponse::Ok().body("Success")
-            }
-            Err(_) => HttpResponse::Ok().body("Invalid services"),
-        }
+        HttpResponse::NoContent().finish()
     } else {
-        match registry_hostname.parse() {
-            Ok(registry) => {
-                registries.push(registry);
-                HttpResponse::Ok().body("Success")
-            }
-            Err(_) => HttpResponse::Ok().body("Invalid registry"),
-        }
+        ApiError::response(
+            StatusCode::NOT_FOUND,
+            format!("Registry `{}` is not known.", registry_hostname),
+        )
+    }
+}
+
+#[post("/api/{registry_hostname}/sync")]
+async fn sync_registry(
+    request: HttpRequest,
+    path: Path<String>,
+    data: Data<State>,
+) -> impl Responder {
+    if let Err(response) = check_admin_authorization(&request, &data).await {
+        return response;
+    }
+
+    let registry_hostname = path.into_inner();
+    let registry = data
+        .registries
+        .lock()
+        .await
+        .iter()
+        .find(|registry| registry.hostname().to_string() == registry_hostname)
+        .cloned();
+    let Some(registry) = registry else {
+        return ApiError::response(
+            StatusCode::NOT_FOUND,
+            format!("Registry `{}` is not known.", registry_hostname),
+        );
+    };
+
+    let hostname = registry.hostname().clone();
+    let success = collect_registry_services_from(data.registries.clone(), registry).await;
+    let Some(registry) = find_registry(&data.registries, &hostname).await else {
+        return ApiError::response(
+            StatusCode::NOT_FOUND,
+            format!("Registry `{}` is not known.", registry_hostname),
+        );
+    };
+
+    if success {
+        HttpResponse::Ok().json(registry.public_services())
+    } else {
+        schedule_collect_retry(data.registries.clone(), hostname);
+
+        ApiError::response(
+            StatusCode::BAD_GATEWAY,
+            registry
+                .last_error()
+                .unwrap_or("Failed to collect public services."),
+        )
     }
 }
 
@@ -85,82 +671,544 @@ pub async fn start_api_server(
     self_registry: Arc<Mutex<Registry>>,
     registries: Arc<Mutex<Vec<Registry>>>,
 ) -> Result<Server> {
+    let token = Env::registry_token();
+    if token.is_none() && Env::registry_tls_ca().is_none() {
+        warn!(
+            "No `{}` or `{}` configured, the API accepts unauthenticated updates.",
+            REGISTRY_TOKEN_ENV, REGISTRY_TLS_CA_ENV
+        );
+    }
+
     let data = Data::new(State {
         self_registry,
         registries,
+        token,
     });
 
+    let tls_config = server_config()?;
+
     let server = HttpServer::new(move || {
         App::new()
             .app_data(data.clone())
             .wrap(Logger::default())
             .service(health)
+            .service(metrics)
             .service(get_self_services)
+            .service(get_registries)
+            .service(get_peers)
+            .service(put_peers)
+            .service(get_conflicts)
+            .service(watch)
             .service(get_registry_services)
             .service(put_registry_services)
+            .service(delete_registry)
+            .service(delete_registry_services)
+            .service(sync_registry)
+            .default_service(to(not_found))
     })
-    .bind(addr)
+    .on_connect(on_connect);
+
+    let server = match tls_config {
+        Some(config) => {
+            info!("API server serving HTTPS on `{}`.", addr);
+            server.bind_rustls_0_23(addr, config)
+        }
+        None => server.bind(addr),
+    }
     .context(format!("Failed to bind API server to `{}`.", addr))?
     .run();
 
     Ok(server)
 }
 
-async fn get(url: Url) -> Result<String> {
-    reqwest::get(url.clone())
-        .await
-        .context(format!("Failed to get `{}`.", url))?
+static CLIENT: LazyLock<Client> = LazyLock::new(|| {
+    configure_client(Client::builder().timeout(Env::registry_request_timeout()))
+        .and_then(|builder| builder.build().context("Failed to build registry client."))
+        .unwrap_or_else(|err| {
+            panic!("{}", err);
+        })
+});
+
+static VERIFIED_CLIENT: LazyLock<Client> = LazyLock::new(|| {
+    configure_client(
+        Client::builder()
+            .timeout(Env::registry_request_timeout())
+            .tls_built_in_root_certs(false),
+    )
+    .and_then(|builder| builder.build().context("Failed to build registry client."))
+    .unwrap_or_else(|err| {
+        panic!("{}", err);
+    })
+});
+
+// the token only goes to registries from `REGISTRY_URLS`, and to discovered registries over
+// `https` verified against `REGISTRY_TLS_CA` alone, so an advertised URL can't collect it
+fn token_client(registry: &Registry, url: &Url, tls_ca: bool) -> Option<&'static Client> {
+    if registry.hops() == 0 {
+        Some(&CLIENT)
+    } else if url.scheme() == "https" && tls_ca {
+        Some(&VERIFIED_CLIENT)
+    } else {
+        None
+    }
+}
+
+fn request(registry: &Registry, method: Method, url: Url) -> RequestBuilder {
+    if let Some(token) = Env::registry_token() {
+        if let Some(client) = token_client(registry, &url, Env::registry_tls_ca().is_some()) {
+            return client.request(method, url).bearer_auth(token);
+        }
+    }
+
+    CLIENT.request(method, url)
+}
+
+#[derive(Debug)]
+struct StatusError {
+    status: reqwest::StatusCode,
+    error: String,
+}
+
+impl fmt::Display for StatusError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Status `{}`: {}", self.status, self.error)
+    }
+}
+
+impl std::error::Error for StatusError {}
+
+fn has_status(err: &anyhow::Error, status: reqwest::StatusCode) -> bool {
+    err.downcast_ref::<StatusError>()
+        .is_some_and(|err| err.status == status)
+}
+
+async fn read_response(url: &Url, response: Response) -> Result<String> {
+    let status = response.status();
+    let body = response
         .text()
         .await
-        .context(format!("Failed to read response from `{}`.", url))
+        .context(format!("Failed to read response from `{}`.", url))?;
+
+    if !status.is_success() {
+        let error = from_str::<ApiError>(&body).map_or(body, |err| err.error);
+        return Err(anyhow::Error::new(StatusError { status, error })
+            .context(format!("Request to `{}` failed.", url)));
+    }
+
+    Ok(body)
 }
 
-async fn put(url: Url, body: String) -> Result<String> {
-    reqwest::Client::new()
-        .put(url.clone())
-        .body(body)
+enum Fetched {
+    NotModified,
+    Modified { body: String, etag: Option<String> },
+}
+
+async fn get(registry: &Registry, url: Url, etag: Option<&str>) -> Result<Fetched> {
+    let mut request = request(registry, Method::GET, url.clone());
+    if let Some(etag) = etag {
+        request = request.header(reqwest::header::IF_NONE_MATCH, etag);
+    }
+    let response = request
         .send()
         .await
-        .context(format!("Failed to put `{}`.", url))?
-        .text()
+        .context(format!("Failed to get `{}`.", url))?;
+
+    if response.status() == reqwest::StatusCode::NOT_MODIFIED {
+        return Ok(Fetched::NotModified);
+    }
+
+    let etag = response
+        .headers()
+        .get(reqwest::header::ETAG)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    let body = read_response(&url, response).await?;
+
+    Ok(Fetched::Modified { body, etag })
+}
+
+async fn put(registry: &Registry, url: Url, body: String) -> Result<(reqwest::StatusCode, String)> {
+    let response = request(registry, Method::PUT, url.clone())
+        .body(body)
+        .send()
         .await
-        .context(format!("Failed to read response from `{}`.", url))
+        .context(format!("Failed to put `{}`.", url))?;
+    let status = response.status();
+
+    Ok((status, read_response(&url, response).await?))
 }
 
-pub async fn collect_registry_services(registries: Arc<Mutex<Vec<Registry>>>) {
-    let mut registries = registries.lock().await;
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Operation {
+    Collect,
+    Dispatch,
+    Gossip,
+}
 
-    for registry in registries.iter_mut() {
-        let mut url = registry.url().clone();
-        url.set_path("/api/self/services");
-
-        info!("Collecting public services from `{}`.", registry.hostname());
-        match get(url).await {
-            Ok(response) => match from_str::<HashSet<String>>(&response) {
-                Ok(services) => {
-                    registry.flush_public_services(services);
-                    info!(
-                        "Collected public services from `{}`: {:?}.",
-                        registry.hostname(),
-                        registry.public_services()
-                    );
-                }
-                Err(_) => {
-                    error!(
-                        "Failed to parse public services from `{}`.\nResponse: {}",
-                        registry.hostname(),
-                        response
-                    );
-                }
-            },
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operation::Collect => write!(f, "collect"),
+            Operation::Dispatch => write!(f, "dispatch"),
+            Operation::Gossip => write!(f, "gossip"),
+        }
+    }
+}
 
-            Err(err) => {
-                error!(
-                    "Failed to fetch public services from `{}`.\nError: {}",
+fn record_request(registry: &Registry, operation: Operation, success: bool) {
+    REGISTRY_REQUESTS
+        .with_label_values(&[
+            &registry.hostname().to_string(),
+            &operation.to_string(),
+            if success { "success" } else { "failure" },
+        ])
+        .inc();
+}
+
+const RETRY_MIN_BACKOFF: Duration = Duration::from_secs(1);
+const RETRY_MAX_BACKOFF: Duration = Duration::from_secs(300);
+const RETRY_MAX_ATTEMPTS: u32 = 10;
+
+static RETRIES: LazyLock<StdMutex<HashSet<(Name, Operation)>>> = LazyLock::new(Default::default);
+
+fn retry_backoff(attempt: u32) -> Duration {
+    RETRY_MIN_BACKOFF
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(RETRY_MAX_BACKOFF)
+}
+
+fn schedule_retry<F, Fut>(hostname: Name, operation: Operation, attempt: F)
+where
+    F: Fn() -> Fut + Send + 'static,
+    Fut: Future<Output = bool> + Send,
+{
+    let key = (hostname.clone(), operation);
+    if !RETRIES.lock().unwrap().insert(key.clone()) {
+        debug!("Retry to {} `{}` is already queued.", operation, hostname);
+        return;
+    }
+
+    tokio::spawn(async move {
+        let mut succeeded = false;
+        for retries in 0..RETRY_MAX_ATTEMPTS {
+            let backoff = retry_backoff(retries);
+            info!(
+                "Retrying to {} `{}` in {:?} (retries: {}).",
+                operation,
+                hostname,
+                backoff,
+                retries + 1
+            );
+            sleep(backoff).await;
+
+            if attempt().await {
+                succeeded = true;
+                break;
+            }
+        }
+
+        if !succeeded {
+            warn!(
+                "Giving up to {} `{}` after {} retries, waiting for the next sync.",
+                operation, hostname, RETRY_MAX_ATTEMPTS
+            );
+        }
+
+        RETRIES.lock().unwrap().remove(&key);
+    });
+}
+
+async fn collect_registry(registry: &Registry) -> Result<Option<(Update, Option<String>)>> {
+    let mut url = registry.url().clone();
+    url.set_path("/api/self/services");
+    url.query_pairs_mut()
+        .append_pair("version", &SERVICES_VERSION.to_string())
+        .append_pair("epoch", &registry.epoch().to_string())
+        .append_pair("since", &registry.generation().to_string());
+
+    info!(
+        "Collecting public services from `{}` since generation `{}`.",
+        registry.hostname(),
+        registry.generation()
+    );
+    let fetched = get(registry, url, registry.collected_etag()).await.context(format!(
+        "Failed to fetch public services from `{}`.",
+        registry.hostname()
+    ))?;
+    let Fetched::Modified {
+        body: response,
+        etag,
+    } = fetched
+    else {
+        return Ok(None);
+    };
+
+    let update = parse_update(&response).context(format!(
+        "Failed to parse public services from `{}`.\nResponse: {}",
+        registry.hostname(),
+        response
+    ))?;
+
+    Ok(Some((update, etag)))
+}
+
+async fn dispatch_service_names(
+    self_registry: &Registry,
+    registry: &Registry,
+    url: Url,
+) -> Result<()> {
+    let names: BTreeSet<_> = self_registry.public_services().keys().collect();
+    let (_, response) = put(registry, url, to_string(&names).unwrap()).await?;
+    if response != "Success" {
+        return Err(anyhow!("Service names were rejected: {}", response));
+    }
+
+    Ok(())
+}
+
+async fn dispatch_registry(self_registry: &Registry, registry: &Registry) -> Result<Option<u64>> {
+    let mut url = registry.url().clone();
+    url.set_path(&format!("/api/{}/services", self_registry.hostname()));
+
+    if registry.is_legacy() {
+        info!(
+            "Dispatching public service names to legacy registry `{}`.",
+            registry.hostname()
+        );
+        dispatch_service_names(self_registry, registry, url)
+            .await
+            .context(format!(
+                "Failed to dispatch public services to `{}`.",
+                registry.hostname()
+            ))?;
+
+        return Ok(None);
+    }
+
+    let update = match registry.dispatched_generation() {
+        Some(generation) => self_registry.update_since(self_registry.epoch(), generation),
+        None => self_registry.snapshot(),
+    };
+    info!(
+        "Dispatching public services to `{}` at generation `{}`.",
+        registry.hostname(),
+        update.generation()
+    );
+
+    let result = match put(registry, url.clone(), to_string(&update).unwrap()).await {
+        Err(err) if update.is_delta() && has_status(&err, reqwest::StatusCode::CONFLICT) => {
+            info!(
+                "Registry `{}` is out of sync, dispatching a full snapshot.",
+                registry.hostname()
+            );
+            put(registry, url.clone(), to_string(&self_registry.snapshot()).unwrap()).await
+        }
+        result => result,
+    };
+    let (status, _) = result.context(format!(
+        "Failed to dispatch public services to `{}`.",
+        registry.hostname()
+    ))?;
+
+    // Registries predating versioned updates answer every update with `200`
+    // and a plain text body instead of `204`, even the ones they can't parse.
+    if status != reqwest::StatusCode::NO_CONTENT {
+        info!(
+            "Registry `{}` doesn't support versioned services, dispatching service names.",
+            registry.hostname()
+        );
+        dispatch_service_names(self_registry, registry, url)
+            .await
+            .context(format!(
+                "Failed to dispatch public services to `{}`.",
+                registry.hostname()
+            ))?;
+
+        return Ok(None);
+    }
+
+    Ok(Some(update.generation()))
+}
+
+async fn collect_registry_services_from(
+    registries: Arc<Mutex<Vec<Registry>>>,
+    registry: Registry,
+) -> bool {
+    let result = collect_registry(&registry).await;
+
+    let mut registries = registries.lock().await;
+    let Some(registry) = registries
+        .iter_mut()
+        .find(|other| other.hostname() == registry.hostname())
+    else {
+        return true;
+    };
+
+    let previous = registry.clone();
+    let result = match result {
+        Ok(None) => {
+            debug!(
+                "Public services from `{}` are not modified.",
+                registry.hostname()
+            );
+            registry.refresh();
+            Ok(())
+        }
+        Ok(Some((update, etag))) => match registry.apply_update(update) {
+            Err(err @ UpdateError::Stale { .. }) => {
+                debug!(
+                    "Ignoring stale public services from `{}`.\nError: {}",
                     registry.hostname(),
                     err
                 );
+                return true;
             }
+            Err(err) => {
+                registry.set_collected_etag(None);
+                Err(anyhow::Error::new(err).context(format!(
+                    "Failed to apply public services from `{}`.",
+                    registry.hostname()
+                )))
+            }
+            Ok(()) => {
+                registry.set_legacy(etag.is_none());
+                registry.set_collected_etag(etag);
+                Ok(())
+            }
+        },
+        Err(err) => Err(err),
+    };
+
+    match result {
+        Ok(()) => {
+            if !previous.is_healthy() {
+                info!("Registry `{}` recovered.", registry.hostname());
+            }
+            publish_changes(&previous, registry);
+            registry.record_success();
+            record_request(registry, Operation::Collect, true);
+            info!(
+                "Collected public services from `{}`: {:?}.",
+                registry.hostname(),
+                registry.public_services()
+            );
+
+            true
+        }
+        Err(err) => {
+            error!("{}\nError: {}", err, err.root_cause());
+            registry.record_error(format!("{:#}", err));
+            record_request(registry, Operation::Collect, false);
+
+            false
+        }
+    }
+}
+
+async fn dispatch_registry_services_to(
+    registries: Arc<Mutex<Vec<Registry>>>,
+    self_registry: Registry,
+    registry: Registry,
+) -> bool {
+    let result = dispatch_registry(&self_registry, &registry).await;
+
+    let mut registries = registries.lock().await;
+    let Some(registry) = registries
+        .iter_mut()
+        .find(|other| other.hostname() == registry.hostname())
+    else {
+        return true;
+    };
+
+    match result {
+        Ok(generation) => {
+            registry.set_dispatched_generation(generation);
+            registry.set_legacy(generation.is_none());
+            registry.record_success();
+            record_request(registry, Operation::Dispatch, true);
+            info!(
+                "Dispatched public services to `{}`: {:?}.",
+                registry.hostname(),
+                self_registry.public_services()
+            );
+
+            true
+        }
+        Err(err) => {
+            error!("{}\nError: {}", err, err.root_cause());
+            registry.record_error(format!("{:#}", err));
+            record_request(registry, Operation::Dispatch, false);
+
+            false
+        }
+    }
+}
+
+async fn find_registry(
+    registries: &Arc<Mutex<Vec<Registry>>>,
+    hostname: &Name,
+) -> Option<Registry> {
+    let registries = registries.lock().await;
+
+    registries
+        .iter()
+        .find(|registry| registry.hostname() == hostname)
+        .cloned()
+}
+
+fn schedule_collect_retry(registries: Arc<Mutex<Vec<Registry>>>, hostname: Name) {
+    schedule_retry(hostname.clone(), Operation::Collect, move || {
+        let registries = registries.clone();
+        let hostname = hostname.clone();
+
+        async move {
+            match find_registry(&registries, &hostname).await {
+                Some(registry) => collect_registry_services_from(registries, registry).await,
+                None => true,
+            }
+        }
+    });
+}
+
+fn schedule_dispatch_retry(
+    self_registry: Arc<Mutex<Registry>>,
+    registries: Arc<Mutex<Vec<Registry>>>,
+    hostname: Name,
+) {
+    schedule_retry(hostname.clone(), Operation::Dispatch, move || {
+        let self_registry = self_registry.clone();
+        let registries = registries.clone();
+        let hostname = hostname.clone();
+
+        async move {
+            let self_registry = self_registry.lock().await.clone();
+            match find_registry(&registries, &hostname).await {
+                Some(registry) => {
+                    dispatch_registry_services_to(registries, self_registry, registry).await
+                }
+                None => true,
+            }
+        }
+    });
+}
+
+pub async fn collect_registry_services(registries: Arc<Mutex<Vec<Registry>>>) {
+    let snapshot = registries.lock().await.clone();
+
+    let results = join_all(snapshot.into_iter().map(|registry| {
+        let registries = registries.clone();
+        let hostname = registry.hostname().clone();
+
+        async move {
+            let success = collect_registry_services_from(registries, registry).await;
+            (hostname, success)
+        }
+    }))
+    .await;
+
+    for (hostname, success) in results {
+        if !success {
+            schedule_collect_retry(registries.clone(), hostname);
         }
     }
 }
@@ -169,29 +1217,832 @@ pub async fn dispatch_registry_services(
     self_registry: Arc<Mutex<Registry>>,
     registries: Arc<Mutex<Vec<Registry>>>,
 ) {
-    let self_registry = self_registry.lock().await;
-    let registries = &*registries.lock().await;
-
-    for registry in registries {
-        let mut url = registry.url().clone();
-        url.set_path(&format!("/api/{}/services", self_registry.hostname()));
-
-        info!("Dispatching public services to `{}`.", registry.hostname());
-        match put(url, to_string(&self_registry.public_services()).unwrap()).await {
-            Ok(_) => {
-                info!(
-                    "Dispatched public services to `{}`: {:?}.",
-                    registry.hostname(),
-                    self_registry.public_services()
-                );
+    let self_snapshot = self_registry.lock().await.clone();
+    let snapshot = registries.lock().await.clone();
+
+    let results = join_all(snapshot.into_iter().map(|registry| {
+        let registries = registries.clone();
+        let self_snapshot = self_snapshot.clone();
+        let hostname = registry.hostname().clone();
+
+        async move {
+            let success = dispatch_registry_services_to(registries, self_snapshot, registry).await;
+            (hostname, success)
+        }
+    }))
+    .await;
+
+    for (hostname, success) in results {
+        if !success {
+            schedule_dispatch_retry(self_registry.clone(), registries.clone(), hostname);
+        }
+    }
+}
+
+async fn gossip_registry(self_registry: &Registry, registry: &Registry) -> Result<Vec<Peer>> {
+    let mut url = registry.url().clone();
+    url.set_path("/api/peers");
+    let introduction = Introduction {
+        url: self_registry.url().to_string(),
+    };
+
+    debug!("Gossiping with `{}`.", registry.hostname());
+    // peers listed without an accepted introduction are not vouched for, so they aren't admitted
+    let response = match put(registry, url, to_string(&introduction).unwrap()).await {
+        Err(err)
+            if has_status(&err, reqwest::StatusCode::NOT_FOUND)
+                || has_status(&err, reqwest::StatusCode::UNAUTHORIZED) =>
+        {
+            debug!(
+                "Registry `{}` did not accept the introduction, ignoring its peers.",
+                registry.hostname()
+            );
+            return Ok(vec![]);
+        }
+        result => result.map(|(_, body)| body),
+    }
+    .context(format!(
+        "Failed to fetch peers from `{}`.",
+        registry.hostname()
+    ))?;
+
+    from_str(&response).context(format!(
+        "Failed to parse peers from `{}`.\nResponse: {}",
+        registry.hostname(),
+        response
+    ))
+}
+
+pub async fn gossip_registries(
+    self_registry: Arc<Mutex<Registry>>,
+    registries: Arc<Mutex<Vec<Registry>>>,
+) {
+    let Some(max_hops) = Env::registry_gossip_hops() else {
+        return;
+    };
+
+    let self_snapshot = self_registry.lock().await.clone();
+    let snapshot = registries.lock().await.clone();
+
+    let results = join_all(snapshot.iter().map(|registry| async {
+        let result = gossip_registry(&self_snapshot, registry).await;
+        record_request(registry, Operation::Gossip, result.is_ok());
+
+        (registry.hostname(), result)
+    }))
+    .await;
+
+    let membership = {
+        let mut registries = registries.lock().await;
+        let mut peers = vec![];
+        for (hostname, result) in results {
+            match result {
+                Ok(advertised) => {
+                    if let Some(registry) = registries
+                        .iter_mut()
+                        .find(|registry| registry.hostname() == hostname)
+                    {
+                        registry.record_success();
+                    }
+                    peers.extend(advertised);
+                }
+                Err(err) => debug!("{}\nError: {}", err, err.root_cause()),
             }
-            Err(err) => {
-                error!(
-                    "Failed to dispatch public services to `{}`.\nError: {}",
+        }
+
+        merge(
+            self_snapshot.hostname(),
+            &mut registries,
+            peers,
+            max_hops,
+            Env::registry_max_peers(),
+            Env::registry_token().is_some(),
+        )
+    };
+
+    for registry in &membership.removed {
+        info!(
+            "Removed registry `{}`, it is unreachable and no longer advertised.",
+            registry.hostname()
+        );
+        publish_removed(registry);
+    }
+    for registry in &membership.added {
+        info!(
+            "Discovered registry `{}` at `{}` (hops: {}).",
+            registry.hostname(),
+            registry.url(),
+            registry.hops()
+        );
+    }
+}
+
+const SYNC_JITTER: f64 = 0.2;
+
+fn jitter(interval: Duration) -> Duration {
+    interval.mul_f64(thread_rng().gen_range(1.0 - SYNC_JITTER..1.0 + SYNC_JITTER))
+}
+
+pub fn max_sync_delay(interval: Duration) -> Duration {
+    interval.mul_f64(1.0 + SYNC_JITTER)
+}
+
+pub async fn sync_registry_services(
+    self_registry: Arc<Mutex<Registry>>,
+    registries: Arc<Mutex<Vec<Registry>>>,
+    interval: Duration,
+) {
+    loop {
+        sleep(jitter(interval)).await;
+
+        info!("Re-syncing public services with all registries.");
+        gossip_registries(self_registry.clone(), registries.clone()).await;
+        collect_registry_services(registries.clone()).await;
+        dispatch_registry_services(self_registry.clone(), registries.clone()).await;
+    }
+}
+
+pub async fn heartbeat_registries(
+    self_registry: Arc<Mutex<Registry>>,
+    registries: Arc<Mutex<Vec<Registry>>>,
+    ttl: Duration,
+) {
+    let interval = (ttl / 3).max(Duration::from_secs(1));
+
+    loop {
+        sleep(interval).await;
+
+        debug!("Sending heartbeats to all registries.");
+        dispatch_registry_services(self_registry.clone(), registries.clone()).await;
+    }
+}
+
+pub async fn expire_registry_services(registries: Arc<Mutex<Vec<Registry>>>, ttl: Duration) {
+    let interval = (ttl / 4).max(Duration::from_secs(1));
+
+    loop {
+        sleep(interval).await;
+
+        let mut registries = registries.lock().await;
+        for registry in registries.iter_mut() {
+            if registry.expire(ttl) {
+                warn!(
+                    "Registry `{}` has not been refreshed for {:?}, marking it unhealthy.",
                     registry.hostname(),
-                    err
+                    ttl
                 );
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::test::TestRequest;
+
+    use crate::{registry::Services, tls::tests::test_certificate};
+
+    #[test]
+    fn test_check_access() {
+        let cert = test_certificate();
+        let test_cases = vec![
+            (None, None, None, Ok(())),
+            (None, None, Some("secret"), Err(StatusCode::UNAUTHORIZED)),
+            (None, Some("Bearer secret"), Some("secret"), Ok(())),
+            (
+                None,
+                Some("Bearer other"),
+                Some("secret"),
+                Err(StatusCode::UNAUTHORIZED),
+            ),
+            (
+                None,
+                Some("Bearer secre"),
+                Some("secret"),
+                Err(StatusCode::UNAUTHORIZED),
+            ),
+            (
+                None,
+                Some("Basic secret"),
+                Some("secret"),
+                Err(StatusCode::UNAUTHORIZED),
+            ),
+            (Some(&cert), None, None, Ok(())),
+            (Some(&cert), None, Some("secret"), Ok(())),
+        ];
+
+        for (cert, authorization, token, expected) in test_cases {
+            assert_eq!(
+                check_access(cert, authorization, token, "alice.com"),
+                expected,
+                "Failed for: {:?}, {:?}",
+                authorization,
+                token
+            );
+        }
+
+        let test_cases = vec![
+            (None, None, Err(StatusCode::FORBIDDEN)),
+            (None, Some("secret"), Err(StatusCode::FORBIDDEN)),
+            (Some("Bearer secret"), Some("secret"), Ok(())),
+        ];
+
+        for (authorization, token, expected) in test_cases {
+            assert_eq!(
+                check_access(Some(&cert), authorization, token, "bob.com"),
+                expected,
+                "Failed for: {:?}, {:?}",
+                authorization,
+                token
+            );
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_peer_certificate_authorization() {
+        let data = Data::new(State {
+            self_registry: Arc::new(Mutex::new("http://bob.com:3000".parse().unwrap())),
+            registries: Arc::new(Mutex::new(vec![
+                "http://alice.com:3000".parse().unwrap(),
+                "http://charlie.com:3000".parse().unwrap(),
+            ])),
+            token: Some("secret".to_string()),
+        });
+        let server = HttpServer::new({
+            let data = data.clone();
+            move || {
+                App::new()
+                    .app_data(data.clone())
+                    .service(put_registry_services)
+                    .service(delete_registry)
+                    .service(delete_registry_services)
+                    .service(sync_registry)
+            }
+        })
+        .on_connect(|_, extensions| {
+            extensions.insert(test_certificate());
+        })
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let addr = server.addrs()[0];
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let client = Client::new();
+        let url = |path: &str| format!("http://{}{}", addr, path);
+        let test_cases = vec![
+            (client.put(url("/api/alice.com/services")).body("[]"), 204),
+            (client.put(url("/api/charlie.com/services")).body("[]"), 403),
+            (client.post(url("/api/alice.com/sync")), 403),
+            (client.delete(url("/api/alice.com/services")), 403),
+            (client.delete(url("/api/charlie.com")), 403),
+            (
+                client.delete(url("/api/charlie.com")).bearer_auth("secret"),
+                204,
+            ),
+        ];
+
+        for (request, status) in test_cases {
+            let request = request.build().unwrap();
+            let description = format!("{} {}", request.method(), request.url());
+            let response = client.execute(request).await.unwrap();
+            assert_eq!(response.status(), status, "Failed for: {}", description);
+        }
+
+        handle.stop(false).await;
+    }
+
+    #[actix_web::test]
+    async fn test_put_registry_services_unauthorized() {
+        let data = Data::new(State {
+            self_registry: Arc::new(Mutex::new("http://alice.com:3000".parse().unwrap())),
+            registries: Arc::new(Mutex::new(vec!["http://bob.com:3000".parse().unwrap()])),
+            token: Some("secret".to_string()),
+        });
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(data.clone())
+                .service(put_registry_services),
+        )
+        .await;
+
+        let request = TestRequest::put()
+            .uri("/api/bob.com/services")
+            .set_payload(r#"["foo"]"#)
+            .to_request();
+        let response = actix_web::test::call_service(&app, request).await;
+        assert_eq!(response.status(), 401);
+        assert!(!data.registries.lock().await[0].has_public_service("foo"));
+
+        let request = TestRequest::put()
+            .uri("/api/bob.com/services")
+            .insert_header((AUTHORIZATION, "Bearer secret"))
+            .set_payload(r#"["foo"]"#)
+            .to_request();
+        let response = actix_web::test::call_service(&app, request).await;
+        assert_eq!(response.status(), 204);
+        assert!(data.registries.lock().await[0].has_public_service("foo"));
+    }
+
+    #[actix_web::test]
+    async fn test_get_registries() {
+        let mut self_registry: Registry = "http://alice.com:3000".parse().unwrap();
+        self_registry.add_private_service("db".to_string(), None);
+        self_registry.add_service_address(
+            "db".to_string(),
+            "caddy".to_string(),
+            "172.18.0.2".parse().unwrap(),
+        );
+        let mut registry: Registry = "http://bob.com:3000".parse().unwrap();
+        registry.add_public_service("app".to_string(), Some(8443));
+        let data = Data::new(State {
+            self_registry: Arc::new(Mutex::new(self_registry)),
+            registries: Arc::new(Mutex::new(vec![registry])),
+            token: Some("secret".to_string()),
+        });
+        let app = actix_web::test::init_service(
+            App::new().app_data(data.clone()).service(get_registries),
+        )
+        .await;
+
+        let request = TestRequest::get().uri("/api/registries").to_request();
+        let response: serde_json::Value =
+            actix_web::test::call_and_read_body_json(&app, request).await;
+        assert_eq!(response["self"]["hostname"], "alice.com");
+        assert_eq!(
+            response["self"]["private_services"]["db"],
+            serde_json::json!([])
+        );
+        assert_eq!(response["registries"][0]["hostname"], "bob.com");
+        assert_eq!(response["registries"][0]["url"], "http://bob.com:3000/");
+        assert_eq!(
+            response["registries"][0]["public_services"]["app"],
+            serde_json::json!([8443])
+        );
+        assert_eq!(response["registries"][0]["healthy"], true);
+        assert!(response["self"].get("service_addresses").is_none());
+
+        let request = TestRequest::get()
+            .uri("/api/registries")
+            .insert_header((AUTHORIZATION, "Bearer secret"))
+            .to_request();
+        let response: serde_json::Value =
+            actix_web::test::call_and_read_body_json(&app, request).await;
+        assert_eq!(
+            response["self"]["service_addresses"]["db"]["caddy"],
+            serde_json::json!(["172.18.0.2"])
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_delete_registry() {
+        let mut registry: Registry = "http://bob.com:3000".parse().unwrap();
+        registry.add_public_service("app".to_string(), None);
+        let data = Data::new(State {
+            self_registry: Arc::new(Mutex::new("http://alice.com:3000".parse().unwrap())),
+            registries: Arc::new(Mutex::new(vec![
+                registry,
+                "http://charlie.com:3000".parse().unwrap(),
+            ])),
+            token: Some("secret".to_string()),
+        });
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(data.clone())
+                .service(delete_registry)
+                .service(delete_registry_services),
+        )
+        .await;
+
+        let test_cases = vec![
+            ("/api/bob.com/services", None, 401),
+            ("/api/bob.com/services", Some("Bearer secret"), 204),
+            ("/api/charlie.com", Some("Bearer secret"), 204),
+            ("/api/charlie.com", Some("Bearer secret"), 404),
+            ("/api/alice.com", Some("Bearer secret"), 409),
+        ];
+
+        for (uri, authorization, status) in test_cases {
+            let mut request = TestRequest::delete().uri(uri);
+            if let Some(authorization) = authorization {
+                request = request.insert_header((AUTHORIZATION, authorization));
+            }
+            let response = actix_web::test::call_service(&app, request.to_request()).await;
+            assert_eq!(response.status(), status, "Failed for: {}", uri);
+        }
+
+        let registries = data.registries.lock().await;
+        assert_eq!(registries.len(), 1);
+        assert!(!registries[0].has_public_service("app"));
+    }
+
+    #[actix_web::test]
+    async fn test_api_error_status() {
+        let data = Data::new(State {
+            self_registry: Arc::new(Mutex::new("http://alice.com:3000".parse().unwrap())),
+            registries: Arc::new(Mutex::new(vec!["http://bob.com:3000".parse().unwrap()])),
+            token: Some("secret".to_string()),
+        });
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(data.clone())
+                .service(get_registry_services)
+                .service(put_registry_services)
+                .default_service(to(not_found)),
+        )
+        .await;
+
+        let test_cases = vec![
+            (TestRequest::get().uri("/api/bob.com/services"), 200),
+            (TestRequest::get().uri("/api/charlie.com/services"), 404),
+            (TestRequest::get().uri("/api/unknown"), 404),
+            (
+                TestRequest::put()
+                    .insert_header((AUTHORIZATION, "Bearer secret"))
+                    .uri("/api/bob.com/services")
+                    .set_payload("invalid"),
+                400,
+            ),
+            (
+                TestRequest::put()
+                    .insert_header((AUTHORIZATION, "Bearer secret"))
+                    .uri("/api/charlie.com/services")
+                    .set_payload("{}"),
+                404,
+            ),
+            (
+                TestRequest::put()
+                    .insert_header((AUTHORIZATION, "Bearer secret"))
+                    .uri("/api/alice.com/services")
+                    .set_payload("{}"),
+                409,
+            ),
+        ];
+
+        for (request, status) in test_cases {
+            let request = request.to_request();
+            let uri = request.uri().clone();
+            let response = actix_web::test::call_service(&app, request).await;
+            assert_eq!(response.status(), status, "Failed for: {}", uri);
+
+            if status >= 400 {
+                let error: ApiError = actix_web::test::read_body_json(response).await;
+                assert_eq!(error.status, status, "Failed for: {}", uri);
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_put_peers() {
+        let data = Data::new(State {
+            self_registry: Arc::new(Mutex::new("http://alice.com:3000".parse().unwrap())),
+            registries: Arc::new(Mutex::new(vec!["http://bob.com:3000".parse().unwrap()])),
+            token: Some("secret".to_string()),
+        });
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(data.clone())
+                .service(get_peers)
+                .service(put_peers),
+        )
+        .await;
+        forget(&"frank.com".parse().unwrap());
+
+        let request = TestRequest::get()
+            .uri("/api/peers?url=http://mallory.com:3000")
+            .to_request();
+        let response = actix_web::test::call_service(&app, request).await;
+        assert_eq!(response.status(), 200);
+
+        let test_cases = vec![
+            (r#"{"url":"http://dave.com:3000"}"#, None, 401),
+            (r#"{"url":"dave.com"}"#, Some("Bearer secret"), 400),
+            ("http://dave.com:3000", Some("Bearer secret"), 400),
+            (
+                r#"{"url":"http://alice.com:3000"}"#,
+                Some("Bearer secret"),
+                200,
+            ),
+            (
+                r#"{"url":"http://dave.com:3000"}"#,
+                Some("Bearer secret"),
+                200,
+            ),
+            (
+                r#"{"url":"http://dave.com:3000"}"#,
+                Some("Bearer secret"),
+                200,
+            ),
+            (
+                r#"{"url":"http://frank.com:3000"}"#,
+                Some("Bearer secret"),
+                200,
+            ),
+        ];
+
+        for (body, authorization, status) in test_cases {
+            let mut request = TestRequest::put().uri("/api/peers").set_payload(body);
+            if let Some(authorization) = authorization {
+                request = request.insert_header((AUTHORIZATION, authorization));
+            }
+            let response = actix_web::test::call_service(&app, request.to_request()).await;
+            assert_eq!(response.status(), status, "Failed for: {}", body);
+        }
+
+        let registries = data.registries.lock().await;
+        let hostnames: Vec<_> = registries
+            .iter()
+            .map(|registry| (registry.hostname().to_string(), registry.hops()))
+            .collect();
+        assert_eq!(
+            hostnames,
+            vec![
+                ("bob.com".to_string(), 0),
+                ("dave.com".to_string(), 1),
+                ("frank.com".to_string(), 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_wait() {
+        let test_cases = vec![
+            ("30s", Some(Duration::from_secs(30))),
+            ("30", Some(Duration::from_secs(30))),
+            ("500ms", Some(Duration::from_millis(500))),
+            ("2m", Some(Duration::from_secs(120))),
+            ("1h", None),
+            ("10m", Some(MAX_WAIT)),
+            ("s", None),
+            ("-1s", None),
+        ];
+
+        for (input, expected) in test_cases {
+            assert_eq!(parse_wait(input), expected, "Failed for: {}", input);
+        }
+    }
+
+    #[test]
+    fn test_token_client() {
+        let bob: Registry = "http://bob.com:3000".parse().unwrap();
+        let mut carol: Registry = "https://carol.com:3000".parse().unwrap();
+        carol.set_hops(1);
+        let mut dave: Registry = "http://dave.com:3000".parse().unwrap();
+        dave.set_hops(1);
+
+        let test_cases = vec![
+            (&bob, false, Some(&*CLIENT)),
+            (&bob, true, Some(&*CLIENT)),
+            (&carol, false, None),
+            (&carol, true, Some(&*VERIFIED_CLIENT)),
+            (&dave, true, None),
+        ];
+
+        for (registry, tls_ca, expected) in test_cases {
+            let client = token_client(registry, registry.url(), tls_ca);
+            assert_eq!(
+                client.map(|client| client as *const Client),
+                expected.map(|client| client as *const Client),
+                "Failed for: {} {}",
+                registry.hostname(),
+                tls_ca
+            );
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_get_self_services_formats() {
+        let mut alice: Registry = "http://alice.com:3000".parse().unwrap();
+        alice.add_public_service("foo".to_string(), Some(8443));
+        alice.commit_public_services(&Services::new());
+        let data = Data::new(State {
+            self_registry: Arc::new(Mutex::new(alice)),
+            registries: Arc::new(Mutex::new(vec![])),
+            token: None,
+        });
+        let app = actix_web::test::init_service(
+            App::new().app_data(data.clone()).service(get_self_services),
+        )
+        .await;
+
+        for uri in ["/api/self/services", "/api/self/services?version=1"] {
+            let request = TestRequest::get().uri(uri).to_request();
+            let names: Vec<String> = actix_web::test::call_and_read_body_json(&app, request).await;
+            assert_eq!(names, vec!["foo"], "Failed for: {}", uri);
+        }
+
+        let request = TestRequest::get()
+            .uri("/api/self/services?version=2")
+            .to_request();
+        let services: Services = actix_web::test::call_and_read_body_json(&app, request).await;
+        assert_eq!(services["foo"], BTreeSet::from([8443]));
+
+        for uri in [
+            "/api/self/services?since=0",
+            "/api/self/services?version=1&since=0",
+            "/api/self/services?version=3",
+        ] {
+            let request = TestRequest::get().uri(uri).to_request();
+            let response = actix_web::test::call_service(&app, request).await;
+            assert_eq!(response.status(), 400, "Failed for: {}", uri);
+        }
+
+        let request = TestRequest::get()
+            .uri("/api/self/services?version=2&since=0")
+            .to_request();
+        let update: Update = actix_web::test::call_and_read_body_json(&app, request).await;
+        assert!(matches!(
+            update,
+            Update::Delta { added, .. } if added["foo"] == BTreeSet::from([8443])
+        ));
+    }
+
+    #[put("/api/{registry_hostname}/services")]
+    async fn put_legacy_services(
+        services: String,
+        received: Data<StdMutex<Vec<HashSet<String>>>>,
+    ) -> impl Responder {
+        match from_str::<HashSet<String>>(&services) {
+            Ok(services) => {
+                received.lock().unwrap().push(services);
+                HttpResponse::Ok().body("Success")
+            }
+            Err(_) => HttpResponse::Ok().body("Invalid services"),
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_dispatch_legacy_registry() {
+        let received: Data<StdMutex<Vec<HashSet<String>>>> = Data::new(StdMutex::new(vec![]));
+        let server = HttpServer::new({
+            let received = received.clone();
+            move || {
+                App::new()
+                    .app_data(received.clone())
+                    .service(put_legacy_services)
+            }
+        })
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let addr = server.addrs()[0];
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let mut alice: Registry = "http://alice.com:3000".parse().unwrap();
+        alice.add_public_service("foo".to_string(), Some(8443));
+        alice.commit_public_services(&Services::new());
+        let mut bob: Registry = format!("http://{}", addr).parse().unwrap();
+
+        assert_eq!(dispatch_registry(&alice, &bob).await.unwrap(), None);
+        bob.set_legacy(true);
+        assert_eq!(dispatch_registry(&alice, &bob).await.unwrap(), None);
+        assert_eq!(
+            *received.lock().unwrap(),
+            vec![HashSet::from(["foo".to_string()]); 2]
+        );
+
+        handle.stop(false).await;
+    }
+
+    #[actix_web::test]
+    async fn test_services_not_modified() {
+        let mut bob: Registry = "http://bob.com:3000".parse().unwrap();
+        bob.add_public_service("foo".to_string(), None);
+        let data = Data::new(State {
+            self_registry: Arc::new(Mutex::new("http://alice.com:3000".parse().unwrap())),
+            registries: Arc::new(Mutex::new(vec![bob])),
+            token: None,
+        });
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(data.clone())
+                .service(get_self_services)
+                .service(get_registry_services),
+        )
+        .await;
+
+        let request = TestRequest::get().uri("/api/bob.com/services").to_request();
+        let response = actix_web::test::call_service(&app, request).await;
+        let etag = response
+            .headers()
+            .get(ETAG)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        let test_cases = vec![
+            ("/api/bob.com/services", None, 200),
+            ("/api/bob.com/services", Some(etag.as_str()), 304),
+            ("/api/bob.com/services", Some("W/\"0-0\", *"), 304),
+            ("/api/bob.com/services", Some("\"0-0\""), 200),
+            ("/api/bob.com/services?wait=10ms", Some(etag.as_str()), 304),
+            ("/api/bob.com/services?wait=soon", Some(etag.as_str()), 400),
+            ("/api/bob.com/services?version=2", Some(etag.as_str()), 200),
+            ("/api/bob.com/services?version=2&since=0", Some(etag.as_str()), 200),
+            ("/api/charlie.com/services", Some(etag.as_str()), 404),
+            ("/api/self/services", Some(etag.as_str()), 200),
+        ];
+
+        for (uri, if_none_match, status) in test_cases {
+            let mut request = TestRequest::get().uri(uri);
+            if let Some(if_none_match) = if_none_match {
+                request = request.insert_header((IF_NONE_MATCH, if_none_match));
+            }
+            let response = actix_web::test::call_service(&app, request.to_request()).await;
+            assert_eq!(response.status(), status, "Failed for: {}", uri);
+
+            if status == 200 || status == 304 {
+                assert!(response.headers().contains_key(ETAG), "Failed for: {}", uri);
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_services_wait_for_generation() {
+        let mut bob: Registry = "http://bob.com:3000".parse().unwrap();
+        bob.add_public_service("foo".to_string(), None);
+        let registries = Arc::new(Mutex::new(vec![bob]));
+        let data = Data::new(State {
+            self_registry: Arc::new(Mutex::new("http://alice.com:3000".parse().unwrap())),
+            registries: registries.clone(),
+            token: None,
+        });
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(data.clone())
+                .service(get_registry_services),
+        )
+        .await;
+
+        let uri = "/api/bob.com/services?version=2&since=0&wait=5s";
+        let request = TestRequest::get().uri(uri).to_request();
+        let response = actix_web::test::call_service(&app, request).await;
+        let etag = response.headers().get(ETAG).unwrap().clone();
+
+        let request = TestRequest::get()
+            .uri(uri)
+            .insert_header((IF_NONE_MATCH, etag.clone()))
+            .to_request();
+        let bump = async {
+            sleep(Duration::from_millis(50)).await;
+            let mut registries = registries.lock().await;
+            let previous = registries[0].clone();
+            let mut source = previous.clone();
+            source.start_epoch();
+            registries[0].apply_update(source.snapshot()).unwrap();
+            publish_changes(&previous, &registries[0]);
+        };
+        let (response, ()) = tokio::join!(
+            timeout(
+                Duration::from_secs(1),
+                actix_web::test::call_service(&app, request)
+            ),
+            bump
+        );
+        let response = response.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_ne!(response.headers().get(ETAG), Some(&etag));
+    }
+
+    #[test]
+    fn test_stable_hash() {
+        assert_eq!(stable_hash(b""), 0xcbf29ce484222325);
+        assert_eq!(stable_hash(b"a"), 0xaf63dc4c8601ec8c);
+    }
+
+    #[test]
+    fn test_retry_backoff() {
+        let test_cases = vec![
+            (0, Duration::from_secs(1)),
+            (1, Duration::from_secs(2)),
+            (5, Duration::from_secs(32)),
+            (8, Duration::from_secs(256)),
+            (9, Duration::from_secs(300)),
+            (100, Duration::from_secs(300)),
+        ];
+
+        for (input, expected) in test_cases {
+            let result = retry_backoff(input);
+            assert_eq!(result, expected, "Failed for input: {:?}", input);
+        }
+    }
+
+    #[test]
+    fn test_jitter() {
+        let interval = Duration::from_secs(100);
+
+        for _ in 0..100 {
+            let result = jitter(interval);
+            assert!(
+                result >= Duration::from_secs(80) && result < max_sync_delay(interval),
+                "Jitter out of range: {:?}",
+                result
+            );
+        }
+    }
+}
@@ -0,0 +1,230 @@
+use std::{collections::BTreeMap, fmt, str::FromStr, sync::Arc};
+
+use anyhow::{anyhow, Error, Result};
+use hickory_server::proto::rr::Name;
+use log::{info, warn};
+use serde::Serialize;
+use tokio::sync::{broadcast::error::RecvError, Mutex};
+
+use crate::{
+    registry::Registry,
+    watch::{subscribe_changes, Visibility},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConflictPolicy {
+    First,
+    All,
+    Reject,
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "first" => Ok(Self::First),
+            "all" => Ok(Self::All),
+            "reject" => Ok(Self::Reject),
+            _ => Err(anyhow!(
+                "Unknown conflict policy `{}`, expected `first`, `all` or `reject`.",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ConflictPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::First => write!(f, "first"),
+            Self::All => write!(f, "all"),
+            Self::Reject => write!(f, "reject"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Conflict {
+    pub service: String,
+    pub registries: Vec<String>,
+}
+
+// self comes first among registries of the same rank, ranked ones come before it
+pub fn prioritize(self_hostname: &Name, registries: &mut [Registry], priority: &[Name]) {
+    let rank = |registry: &Registry| {
+        let position = priority
+            .iter()
+            .position(|hostname| hostname == registry.hostname())
+            .unwrap_or(priority.len());
+
+        (position, registry.hostname() != self_hostname)
+    };
+
+    registries.sort_by(|a, b| {
+        rank(a)
+            .cmp(&rank(b))
+            .then_with(|| a.hostname().cmp(b.hostname()))
+    });
+}
+
+pub fn conflicts(
+    self_registry: &Registry,
+    registries: &[Registry],
+    priority: &[Name],
+) -> Vec<Conflict> {
+    let mut registries: Vec<Registry> = [self_registry]
+        .into_iter()
+        .chain(registries.iter().filter(|registry| registry.is_healthy()))
+        .cloned()
+        .collect();
+    prioritize(self_registry.hostname(), &mut registries, priority);
+
+    let mut claims: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+    for registry in &registries {
+        for service in registry.public_services().keys() {
+            claims
+                .entry(service)
+                .or_default()
+                .push(registry.hostname().to_string());
+        }
+    }
+
+    claims
+        .into_iter()
+        .filter(|(_, registries)| registries.len() > 1)
+        .map(|(service, registries)| Conflict {
+            service: service.to_string(),
+            registries,
+        })
+        .collect()
+}
+
+pub async fn watch_conflicts(
+    self_registry: Arc<Mutex<Registry>>,
+    registries: Arc<Mutex<Vec<Registry>>>,
+    policy: ConflictPolicy,
+    priority: Vec<Name>,
+) {
+    let mut changes = subscribe_changes();
+    let mut known: Vec<Conflict> = vec![];
+
+    loop {
+        let self_snapshot = self_registry.lock().await.clone();
+        let snapshot = registries.lock().await.clone();
+        let current = conflicts(&self_snapshot, &snapshot, &priority);
+
+        for conflict in current.iter().filter(|conflict| !known.contains(conflict)) {
+            warn!(
+                "Public service `{}` is claimed by registries `{}`, resolving with policy `{}`.",
+                conflict.service,
+                conflict.registries.join("`, `"),
+                policy
+            );
+        }
+        for conflict in &known {
+            if !current
+                .iter()
+                .any(|current| current.service == conflict.service)
+            {
+                info!(
+                    "Public service `{}` is no longer in conflict.",
+                    conflict.service
+                );
+            }
+        }
+
+        known = current;
+
+        loop {
+            match changes.recv().await {
+                Ok(change) if change.visibility == Visibility::Public => break,
+                Ok(_) => continue,
+                Err(RecvError::Lagged(_)) => break,
+                Err(RecvError::Closed) => return,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_conflict_policy_from_str() {
+        let test_cases = vec![
+            ("first", Some(ConflictPolicy::First)),
+            ("ALL", Some(ConflictPolicy::All)),
+            (" reject ", Some(ConflictPolicy::Reject)),
+            ("random", None),
+        ];
+
+        for (input, expected) in test_cases {
+            assert_eq!(input.parse().ok(), expected, "Failed for: {}", input);
+        }
+    }
+
+    #[test]
+    fn test_conflicts() {
+        let mut self_registry: Registry = "http://alice.com:3000".parse().unwrap();
+        self_registry.add_public_service("app".to_string(), None);
+        self_registry.add_private_service("db".to_string(), None);
+
+        let mut registries: Vec<Registry> = vec![
+            "http://zed.com:3000".parse().unwrap(),
+            "http://erin.com:3000".parse().unwrap(),
+            "http://bob.com:3000".parse().unwrap(),
+            "http://dave.com:3000".parse().unwrap(),
+        ];
+        registries[0].set_hops(1);
+        for registry in registries.iter_mut() {
+            registry.add_public_service("web".to_string(), None);
+        }
+        registries[3].add_public_service("app".to_string(), None);
+        registries[3].add_public_service("db".to_string(), None);
+
+        let priority: Vec<Name> = vec!["dave.com".parse().unwrap(), "zed.com".parse().unwrap()];
+        let self_priority: Vec<Name> = vec![
+            "zed.com".parse().unwrap(),
+            "alice.com".parse().unwrap(),
+            "dave.com".parse().unwrap(),
+        ];
+        let test_cases = vec![
+            (
+                vec![],
+                vec!["alice.com", "dave.com"],
+                vec!["bob.com", "dave.com", "erin.com", "zed.com"],
+            ),
+            (
+                priority,
+                vec!["dave.com", "alice.com"],
+                vec!["dave.com", "zed.com", "bob.com", "erin.com"],
+            ),
+            (
+                self_priority,
+                vec!["alice.com", "dave.com"],
+                vec!["zed.com", "dave.com", "bob.com", "erin.com"],
+            ),
+        ];
+
+        for (priority, app, web) in test_cases {
+            assert_eq!(
+                conflicts(&self_registry, &registries, &priority),
+                vec![
+                    Conflict {
+                        service: "app".to_string(),
+                        registries: app.into_iter().map(str::to_string).collect(),
+                    },
+                    Conflict {
+                        service: "web".to_string(),
+                        registries: web.into_iter().map(str::to_string).collect(),
+                    },
+                ],
+                "Failed for: {:?}",
+                priority
+            );
+        }
+    }
+}
@@ -1,11 +1,54 @@
 pub const SERVER_LISTEN_ENV: &str = "DNS_SERVER_LISTEN";
 pub const DEFAULT_SERVER_LISTEN: &str = "0.0.0.0:53";
 
+pub const SERVER_TCP_TIMEOUT_ENV: &str = "DNS_SERVER_TCP_TIMEOUT";
+pub const DEFAULT_SERVER_TCP_TIMEOUT: &str = "5";
+
 pub const REGISTRY_LISTEN_ENV: &str = "SERVICE_REGISTRY_LISTEN";
 pub const DEFAULT_REGISTRY_LISTEN: &str = "0.0.0.0:3000";
 
+pub const REGISTRY_TTL_ENV: &str = "REGISTRY_TTL";
+pub const DEFAULT_REGISTRY_TTL: &str = "900";
+
+pub const DOCKER_HOST_ENV: &str = "DOCKER_HOST";
+pub const DOCKER_TLS_VERIFY_ENV: &str = "DOCKER_TLS_VERIFY";
+pub const DOCKER_CERT_PATH_ENV: &str = "DOCKER_CERT_PATH";
+pub const DOCKER_SOCKET_ENV: &str = "DOCKER_SOCKET";
+pub const DEFAULT_DOCKER_SOCKET: &str = "/var/run/docker.sock";
+
+pub const DOCKER_EVENTS_DEBOUNCE_ENV: &str = "DOCKER_EVENTS_DEBOUNCE";
+pub const DEFAULT_DOCKER_EVENTS_DEBOUNCE: &str = "500";
+
+pub const SERVICE_NETWORK_ENV: &str = "SERVICE_NETWORK";
+
 pub const REGISTRY_HOSTNAME_ENV: &str = "SELF_HOSTNAME";
 pub const REGISTRY_URLS_ENV: &str = "REGISTRY_URLS";
 
-pub const PUBLIC_SERVICE_TLD: &str = "public";
-pub const PRIVATE_SERVICE_TLD: &str = "private";
+pub const REGISTRY_GOSSIP_HOPS_ENV: &str = "REGISTRY_GOSSIP_HOPS";
+pub const DEFAULT_REGISTRY_GOSSIP_HOPS: &str = "2";
+
+pub const REGISTRY_MAX_PEERS_ENV: &str = "REGISTRY_MAX_PEERS";
+pub const DEFAULT_REGISTRY_MAX_PEERS: &str = "64";
+
+pub const REGISTRY_TOKEN_ENV: &str = "REGISTRY_TOKEN";
+
+pub const REGISTRY_TLS_CERT_ENV: &str = "REGISTRY_TLS_CERT";
+pub const REGISTRY_TLS_KEY_ENV: &str = "REGISTRY_TLS_KEY";
+pub const REGISTRY_TLS_CA_ENV: &str = "REGISTRY_TLS_CA";
+
+pub const REGISTRY_REQUEST_TIMEOUT_ENV: &str = "REGISTRY_REQUEST_TIMEOUT";
+pub const DEFAULT_REGISTRY_REQUEST_TIMEOUT: &str = "10";
+
+pub const REGISTRY_SYNC_INTERVAL_ENV: &str = "REGISTRY_SYNC_INTERVAL";
+pub const DEFAULT_REGISTRY_SYNC_INTERVAL: &str = "300";
+
+pub const SERVICE_CONFLICT_POLICY_ENV: &str = "SERVICE_CONFLICT_POLICY";
+pub const DEFAULT_SERVICE_CONFLICT_POLICY: &str = "first";
+
+pub const SERVICE_PRIORITY_ENV: &str = "SERVICE_PRIORITY";
+
+pub const PUBLIC_SERVICE_TLD_ENV: &str = "PUBLIC_SERVICE_TLD";
+pub const DEFAULT_PUBLIC_SERVICE_TLD: &str = "public";
+
+pub const PRIVATE_SERVICE_TLD_ENV: &str = "PRIVATE_SERVICE_TLD";
+pub const DEFAULT_PRIVATE_SERVICE_TLD: &str = "private";
@@ -6,7 +6,10 @@ use hickory_server::{
     authority::MessageResponseBuilder,
     proto::{
         op::{Header, ResponseCode},
-        rr::{rdata::A, RData, Record, RecordData},
+        rr::{
+            rdata::{A, AAAA, SOA},
+            Name, RData, Record, RecordData, RecordType,
+        },
     },
     server::{Request, RequestHandler, ResponseHandler, ResponseInfo},
 };
@@ -14,48 +17,83 @@ use log::{debug, error, info};
 use tokio::sync::Mutex;
 
 use crate::{
-    constants::{PRIVATE_SERVICE_TLD, PUBLIC_SERVICE_TLD},
+    conflict::{prioritize, ConflictPolicy},
+    env::Env,
+    metrics::{DNS_QUERIES, DNS_REQUEST_DURATION},
     registry::Registry,
 };
 
+const ADDRESS_RECORD_TYPES: [RecordType; 2] = [RecordType::A, RecordType::AAAA];
+
+enum Answer {
+    Records(Vec<RData>),
+    NoData,
+    NxDomain,
+    Refused,
+    Unsupported,
+}
+
 pub struct Dns {
     self_registry: Arc<Mutex<Registry>>,
     registries: Arc<Mutex<Vec<Registry>>>,
+    policy: ConflictPolicy,
+    priority: Vec<Name>,
 }
 
 impl Dns {
-    pub fn new(self_registry: Arc<Mutex<Registry>>, registries: Arc<Mutex<Vec<Registry>>>) -> Self {
+    pub fn new(
+        self_registry: Arc<Mutex<Registry>>,
+        registries: Arc<Mutex<Vec<Registry>>>,
+        policy: ConflictPolicy,
+        priority: Vec<Name>,
+    ) -> Self {
         Self {
             self_registry,
             registries,
+            policy,
+            priority,
         }
     }
 
-    pub fn query_upstream(name: &str) -> Option<RData> {
-        lookup_host(name)
+    pub fn query_upstream(name: &str, record_type: RecordType) -> Option<RData> {
+        lookup_host(name.trim_end_matches('.'))
             .ok()?
             .into_iter()
-            .find_map(|addr| match addr {
-                IpAddr::V4(ip) => Some(ip),
+            .find_map(|addr| match (addr, record_type) {
+                (IpAddr::V4(ip), RecordType::A) => Some(A(ip).into_rdata()),
+                (IpAddr::V6(ip), RecordType::AAAA) => Some(AAAA(ip).into_rdata()),
                 _ => None,
             })
-            .map(|ip| A(ip).into_rdata())
     }
 
-    async fn query_self_registry(&self, service: &str) -> Option<RData> {
-        let self_registry = (&*self.self_registry.lock().await).clone();
+    fn zone_name(tld: &str) -> Name {
+        Name::from_ascii(format!("{}.", tld)).unwrap_or_else(|_| Name::root())
+    }
 
-        if self_registry.has_public_service(service) || self_registry.has_private_service(service) {
-            if self_registry.has_public_service(service) {
-                debug!("Found public service `{}` in self registry", service);
-            } else {
-                debug!("Found private service `{}` in self registry", service);
+    fn split_name(name: &Name) -> (String, Option<Name>) {
+        let fqdn = name.to_lowercase().to_string();
+        let fqdn = fqdn.trim_end_matches('.');
+
+        for tld in [Env::public_service_tld(), Env::private_service_tld()] {
+            if let Some(service) = fqdn.strip_suffix(&format!(".{}", tld)) {
+                return (service.to_string(), Some(Self::zone_name(&tld)));
             }
+        }
+
+        (fqdn.to_string(), None)
+    }
 
-            self_registry
-                .try_into()
-                .map_err(|err| error!("{}", err))
-                .ok()
+    async fn query_self_registry(&self, service: &str) -> Option<Registry> {
+        let self_registry = (*self.self_registry.lock().await).clone();
+
+        if self_registry.has_public_service(service) {
+            debug!("Found public service `{}` in self registry", service);
+
+            Some(self_registry)
+        } else if self_registry.has_private_service(service) {
+            debug!("Found private service `{}` in self registry", service);
+
+            Some(self_registry)
         } else {
             debug!("Service `{}` not found in self registry", service);
 
@@ -63,28 +101,216 @@ impl Dns {
         }
     }
 
-    async fn query_registries(&self, service: &str) -> Option<RData> {
-        let registries = (&*self.registries.lock().await).clone();
+    async fn query_registries(&self, service: &str) -> Vec<Registry> {
+        let registries: Vec<Registry> = (*self.registries.lock().await)
+            .iter()
+            .filter(|registry| {
+                let healthy = registry.is_healthy();
+                if !healthy && registry.has_public_service(service) {
+                    debug!(
+                        "Skipping unhealthy registry `{}` for service `{}`",
+                        registry.hostname(),
+                        service
+                    );
+                }
 
-        for registry in registries.iter() {
-            if registry.has_public_service(service) {
-                debug!(
-                    "Found public service `{}` in registry `{}`",
-                    service,
-                    registry.hostname()
-                );
+                healthy
+            })
+            .filter(|registry| registry.has_public_service(service))
+            .cloned()
+            .collect();
+        if registries.is_empty() {
+            debug!("Service `{}` not found in any registry", service);
+        }
+        for registry in &registries {
+            debug!(
+                "Found public service `{}` in registry `{}`",
+                service,
+                registry.hostname()
+            );
+        }
 
-                return registry
-                    .clone()
-                    .try_into()
-                    .map_err(|err| error!("{}", err))
-                    .ok();
+        registries
+    }
+
+    fn split_srv_service(service: &str) -> Option<&str> {
+        let (label, rest) = service.split_once('.')?;
+        let (proto, service) = rest.split_once('.')?;
+
+        if label.starts_with('_') && proto == "_tcp" {
+            Some(service)
+        } else {
+            None
+        }
+    }
+
+    async fn resolve(&self, name: &Name, service: &str, query_type: RecordType) -> Answer {
+        let srv_service = Self::split_srv_service(service);
+        let service = srv_service.unwrap_or(service);
+
+        let zone = Self::split_name(name).1;
+        let is_authoritative = zone.is_some();
+        let is_public = zone == Some(Self::zone_name(&Env::public_service_tld()));
+        let (mut registries, outcome) = match self.query_self_registry(service).await {
+            Some(registry) if !registry.has_public_service(service) => (vec![registry], "self"),
+            Some(registry) => {
+                let self_hostname = registry.hostname().clone();
+                let mut registries = vec![registry];
+                registries.extend(self.query_registries(service).await);
+                prioritize(&self_hostname, &mut registries, &self.priority);
+                (registries, "self")
+            }
+            None => match self.query_registries(service).await {
+                registries if registries.is_empty() => (registries, "upstream"),
+                mut registries => {
+                    let self_hostname = self.self_registry.lock().await.hostname().clone();
+                    prioritize(&self_hostname, &mut registries, &self.priority);
+                    (registries, "peer")
+                }
+            },
+        };
+
+        if registries.len() > 1 {
+            match self.policy {
+                ConflictPolicy::First => registries.truncate(1),
+                ConflictPolicy::All => {}
+                ConflictPolicy::Reject => {
+                    debug!(
+                        "Rejecting service `{}` claimed by {} registries",
+                        service,
+                        registries.len()
+                    );
+                    DNS_QUERIES.with_label_values(&["conflict"]).inc();
+
+                    return Answer::Refused;
+                }
             }
         }
 
-        debug!("Service `{}` not found in any registry", service);
+        if registries.is_empty() {
+            if is_authoritative {
+                DNS_QUERIES.with_label_values(&["nxdomain"]).inc();
 
-        None
+                return Answer::NxDomain;
+            }
+            if !matches!(
+                query_type,
+                RecordType::A | RecordType::AAAA | RecordType::ANY
+            ) {
+                DNS_QUERIES.with_label_values(&["refused"]).inc();
+
+                return Answer::Unsupported;
+            }
+        }
+
+        let is_srv = srv_service.is_some();
+        let answer = if registries.is_empty() {
+            Self::resolve_registry(name, service, is_srv, is_public, query_type, None)
+        } else {
+            Self::combine_answers(registries.into_iter().map(|registry| {
+                Self::resolve_registry(name, service, is_srv, is_public, query_type, Some(registry))
+            }))
+        };
+        let outcome = match answer {
+            Answer::NxDomain => "nxdomain",
+            _ => outcome,
+        };
+        DNS_QUERIES.with_label_values(&[outcome]).inc();
+
+        answer
+    }
+
+    fn combine_answers(answers: impl Iterator<Item = Answer>) -> Answer {
+        let mut records: Vec<RData> = vec![];
+        let mut no_data = false;
+
+        for answer in answers {
+            match answer {
+                Answer::Records(data) => {
+                    for data in data {
+                        if !records.contains(&data) {
+                            records.push(data);
+                        }
+                    }
+                }
+                Answer::NoData => no_data = true,
+                Answer::NxDomain | Answer::Refused | Answer::Unsupported => {}
+            }
+        }
+
+        if !records.is_empty() {
+            Answer::Records(records)
+        } else if no_data {
+            Answer::NoData
+        } else {
+            Answer::NxDomain
+        }
+    }
+
+    fn resolve_registry(
+        name: &Name,
+        service: &str,
+        is_srv: bool,
+        is_public: bool,
+        query_type: RecordType,
+        registry: Option<Registry>,
+    ) -> Answer {
+        if is_srv {
+            let data = match (query_type, &registry) {
+                (RecordType::SRV | RecordType::ANY, Some(registry)) => {
+                    registry.srv_rdata(service, is_public)
+                }
+                _ => vec![],
+            };
+
+            return if !data.is_empty() {
+                Answer::Records(data)
+            } else if registry.is_some() {
+                Answer::NoData
+            } else {
+                Answer::NxDomain
+            };
+        }
+
+        let network = Env::service_network();
+        let resolve = |record_type: RecordType| match &registry {
+            Some(registry) => registry
+                .try_into_service_rdata(service, network.as_deref(), record_type)
+                .map_err(|err| debug!("{}", err))
+                .ok(),
+            None => Self::query_upstream(&name.to_string(), record_type),
+        };
+
+        let data: Vec<RData> = match query_type {
+            RecordType::A | RecordType::AAAA => resolve(query_type).into_iter().collect(),
+            RecordType::ANY => ADDRESS_RECORD_TYPES
+                .into_iter()
+                .filter_map(resolve)
+                .collect(),
+            _ => vec![],
+        };
+
+        if !data.is_empty() {
+            Answer::Records(data)
+        } else if registry.is_some()
+            || ADDRESS_RECORD_TYPES
+                .into_iter()
+                .any(|record_type| record_type != query_type && resolve(record_type).is_some())
+        {
+            Answer::NoData
+        } else {
+            Answer::NxDomain
+        }
+    }
+
+    async fn soa_record(&self, zone: Name) -> Record {
+        let mname = self.self_registry.lock().await.hostname().clone();
+        let rname = Name::from_ascii("hostmaster")
+            .and_then(|name| name.append_domain(&mname))
+            .unwrap_or_else(|_| mname.clone());
+        let soa = SOA::new(mname, rname, 1, 3600, 600, 86400, 0);
+
+        Record::from_rdata(zone, 0, soa.into_rdata())
     }
 }
 
@@ -95,42 +321,89 @@ impl RequestHandler for Dns {
         request: &Request,
         mut response_handle: R,
     ) -> ResponseInfo {
-        let name = request.query().name();
-        debug!("Received DNS query for name: `{}`", name);
-        let service = name
-            .to_string()
-            .trim_end_matches('.')
-            .trim_end_matches(&format!(".{}", PUBLIC_SERVICE_TLD))
-            .trim_end_matches(&format!(".{}", PRIVATE_SERVICE_TLD))
-            .to_string();
+        let _timer = DNS_REQUEST_DURATION.start_timer();
+
+        let name = Name::from(request.query().name());
+        let query_type = request.query().query_type();
+        debug!(
+            "Received DNS query for name: `{}`, type: `{}`",
+            name, query_type
+        );
+        let (service, zone) = Self::split_name(&name);
         debug!("Extracted service name: `{}`", service);
 
         let mut header = Header::response_from_request(request.header());
-        header.set_authoritative(true);
+        header.set_authoritative(zone.is_some());
         header.set_recursion_available(true);
 
         let builder = MessageResponseBuilder::from_message_request(request);
 
-        let data = self
-            .query_self_registry(&service)
-            .await
-            .or(self.query_registries(&service).await)
-            .or(Self::query_upstream(&name.to_string()));
-        let result = match data {
-            Some(data) => {
-                info!("Responding with A record for `{}`: `{}`", name, data);
+        let result = match self.resolve(&name, &service, query_type).await {
+            Answer::Records(data) => {
+                let records: Vec<Record> = data
+                    .into_iter()
+                    .map(|data| Record::from_rdata(name.clone(), 0, data))
+                    .collect();
+                info!(
+                    "Responding with {} record for `{}`: {:?}",
+                    query_type,
+                    name,
+                    records
+                        .iter()
+                        .filter_map(|record| record.data().map(ToString::to_string))
+                        .collect::<Vec<_>>()
+                );
 
-                let records = vec![Record::from_rdata(name.into(), 0, data)];
                 let response = builder.build(header, records.iter(), &[], &[], &[]);
 
                 response_handle.send_response(response).await
             }
 
-            None => {
-                info!("No A record found for `{}`", name);
+            Answer::NoData => {
+                info!("No {} record found for `{}`", query_type, name);
+
+                let soa = match zone {
+                    Some(zone) => vec![self.soa_record(zone).await],
+                    None => vec![],
+                };
+                let response = builder.build(header, &[], &[], soa.iter(), &[]);
+
+                response_handle.send_response(response).await
+            }
+
+            Answer::Refused => {
+                info!(
+                    "Refusing to resolve `{}`, it is claimed by multiple registries",
+                    name
+                );
+
+                header.set_response_code(ResponseCode::Refused);
+                let response = builder.build(header, &[], &[], &[], &[]);
+
+                response_handle.send_response(response).await
+            }
+
+            Answer::Unsupported => {
+                info!(
+                    "Refusing {} query for `{}` outside the service zones",
+                    query_type, name
+                );
+
+                header.set_response_code(ResponseCode::Refused);
+                let response = builder.build(header, &[], &[], &[], &[]);
+
+                response_handle.send_response(response).await
+            }
+
+            Answer::NxDomain => {
+                info!("No such name `{}`", name);
 
                 header.set_response_code(ResponseCode::NXDomain);
-                let response = builder.build_no_records(header);
+                let soa = match zone {
+                    Some(zone) => vec![self.soa_record(zone).await],
+                    None => vec![],
+                };
+                let response = builder.build(header, &[], &[], soa.iter(), &[]);
 
                 response_handle.send_response(response).await
             }
@@ -145,3 +418,239 @@ impl RequestHandler for Dns {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use hickory_server::proto::rr::rdata::SRV;
+
+    #[test]
+    fn test_query_upstream() {
+        let data = Dns::query_upstream("127.0.0.1", RecordType::A);
+        assert_eq!(data, Some(A(Ipv4Addr::LOCALHOST).into_rdata()));
+        assert_eq!(Dns::query_upstream("127.0.0.1", RecordType::AAAA), None);
+
+        let data = Dns::query_upstream("::1", RecordType::AAAA);
+        assert_eq!(data, Some(AAAA(Ipv6Addr::LOCALHOST).into_rdata()));
+        assert_eq!(Dns::query_upstream("::1", RecordType::A), None);
+
+        let data = Dns::query_upstream("localhost.", RecordType::A);
+        assert_eq!(data, Some(A(Ipv4Addr::LOCALHOST).into_rdata()));
+    }
+
+    #[test]
+    fn test_split_name() {
+        let test_cases = vec![
+            ("service.public.", "service", Some("public.")),
+            ("sub.service.private.", "sub.service", Some("private.")),
+            ("APP.Public.", "app", Some("public.")),
+            ("service.", "service", None),
+            ("example.com.", "example.com", None),
+        ];
+
+        for (input, service, zone) in test_cases {
+            let (result_service, result_zone) = Dns::split_name(&Name::from_ascii(input).unwrap());
+            assert_eq!(result_service, service, "Failed for input: {:?}", input);
+            assert_eq!(
+                result_zone,
+                zone.map(|zone| Name::from_ascii(zone).unwrap()),
+                "Failed for input: {:?}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_split_srv_service() {
+        let test_cases = vec![
+            ("_https._tcp.service", Some("service")),
+            ("_http._tcp.sub.service", Some("sub.service")),
+            ("_https._udp.service", None),
+            ("https._tcp.service", None),
+            ("service", None),
+            ("sub.service", None),
+        ];
+
+        for (input, expected) in test_cases {
+            let result = Dns::split_srv_service(input);
+            assert_eq!(result, expected, "Failed for input: {:?}", input);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resolve() {
+        let mut self_registry: Registry = "http://localhost:3000".parse().unwrap();
+        self_registry.add_public_service("service".to_string(), Some(8443));
+        self_registry.add_private_service("service".to_string(), Some(9443));
+        let dns = Dns::new(
+            Arc::new(Mutex::new(self_registry)),
+            Arc::new(Mutex::new(vec![])),
+            ConflictPolicy::First,
+            vec![],
+        );
+        let name = Name::from_ascii("service.public.").unwrap();
+
+        match dns.resolve(&name, "service", RecordType::A).await {
+            Answer::Records(data) => {
+                assert_eq!(data, vec![A(Ipv4Addr::LOCALHOST).into_rdata()])
+            }
+            _ => panic!("Expected A record for `{}`", name),
+        }
+        assert!(matches!(
+            dns.resolve(&name, "service", RecordType::MX).await,
+            Answer::NoData
+        ));
+        assert!(matches!(
+            dns.resolve(&name, "service", RecordType::TXT).await,
+            Answer::NoData
+        ));
+
+        let name = Name::from_ascii("_https._tcp.service.public.").unwrap();
+        match dns
+            .resolve(&name, "_https._tcp.service", RecordType::SRV)
+            .await
+        {
+            Answer::Records(data) => {
+                let target = Name::from_ascii("localhost.").unwrap();
+                assert_eq!(data, vec![SRV::new(0, 0, 8443, target).into_rdata()])
+            }
+            _ => panic!("Expected SRV record for `{}`", name),
+        }
+        let private_name = Name::from_ascii("_https._tcp.service.private.").unwrap();
+        match dns
+            .resolve(&private_name, "_https._tcp.service", RecordType::SRV)
+            .await
+        {
+            Answer::Records(data) => {
+                let target = Name::from_ascii("localhost.").unwrap();
+                assert_eq!(
+                    data,
+                    vec![
+                        SRV::new(0, 0, 8443, target.clone()).into_rdata(),
+                        SRV::new(0, 0, 9443, target).into_rdata(),
+                    ]
+                )
+            }
+            _ => panic!("Expected SRV record for `{}`", private_name),
+        }
+        assert!(matches!(
+            dns.resolve(&name, "_https._tcp.service", RecordType::A)
+                .await,
+            Answer::NoData
+        ));
+
+        let name = Name::from_ascii("_https._tcp.unknown.public.").unwrap();
+        assert!(matches!(
+            dns.resolve(&name, "_https._tcp.unknown", RecordType::SRV)
+                .await,
+            Answer::NxDomain
+        ));
+
+        let name = Name::from_ascii("unknown.public.").unwrap();
+        assert!(matches!(
+            dns.resolve(&name, "unknown", RecordType::MX).await,
+            Answer::NxDomain
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_resolve_outside_zones() {
+        let dns = Dns::new(
+            Arc::new(Mutex::new("http://alice.com:3000".parse().unwrap())),
+            Arc::new(Mutex::new(vec![])),
+            ConflictPolicy::First,
+            vec![],
+        );
+        let name = Name::from_ascii("localhost.").unwrap();
+
+        match dns.resolve(&name, "localhost", RecordType::A).await {
+            Answer::Records(data) => {
+                assert_eq!(data, vec![A(Ipv4Addr::LOCALHOST).into_rdata()])
+            }
+            _ => panic!("Expected A record for `{}`", name),
+        }
+        assert!(matches!(
+            dns.resolve(&name, "localhost", RecordType::MX).await,
+            Answer::Unsupported
+        ));
+        assert!(matches!(
+            dns.resolve(&name, "localhost", RecordType::TXT).await,
+            Answer::Unsupported
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_resolve_conflict() {
+        let mut self_registry: Registry = "http://localhost:3000".parse().unwrap();
+        self_registry.add_public_service("service".to_string(), None);
+        let mut registry: Registry = "http://127.0.0.2:3000".parse().unwrap();
+        registry.add_public_service("service".to_string(), None);
+        registry.add_public_service("other".to_string(), None);
+
+        let local = A(Ipv4Addr::LOCALHOST).into_rdata();
+        let peer = A(Ipv4Addr::new(127, 0, 0, 2)).into_rdata();
+        let test_cases = vec![
+            (ConflictPolicy::First, Some(vec![local.clone()])),
+            (ConflictPolicy::All, Some(vec![local, peer.clone()])),
+            (ConflictPolicy::Reject, None),
+        ];
+
+        for (policy, expected) in test_cases {
+            let dns = Dns::new(
+                Arc::new(Mutex::new(self_registry.clone())),
+                Arc::new(Mutex::new(vec![registry.clone()])),
+                policy,
+                vec![],
+            );
+
+            let name = Name::from_ascii("service.public.").unwrap();
+            match (
+                dns.resolve(&name, "service", RecordType::A).await,
+                &expected,
+            ) {
+                (Answer::Records(data), Some(expected)) => {
+                    assert_eq!(&data, expected, "Failed for: {}", policy)
+                }
+                (Answer::Refused, None) => {}
+                _ => panic!("Unexpected answer for `{}` with policy `{}`", name, policy),
+            }
+
+            let name = Name::from_ascii("other.public.").unwrap();
+            match dns.resolve(&name, "other", RecordType::A).await {
+                Answer::Records(data) => {
+                    assert_eq!(data, vec![peer.clone()], "Failed for: {}", policy)
+                }
+                _ => panic!("Expected A record for `{}`", name),
+            }
+        }
+    }
+
+    #[test]
+    fn test_combine_answers() {
+        let a = A(Ipv4Addr::LOCALHOST).into_rdata();
+        let b = A(Ipv4Addr::new(127, 0, 0, 2)).into_rdata();
+
+        match Dns::combine_answers(
+            vec![
+                Answer::Records(vec![a.clone()]),
+                Answer::NoData,
+                Answer::Records(vec![b.clone(), a.clone()]),
+            ]
+            .into_iter(),
+        ) {
+            Answer::Records(data) => assert_eq!(data, vec![a, b]),
+            _ => panic!("Expected combined records"),
+        }
+        assert!(matches!(
+            Dns::combine_answers(vec![Answer::NoData, Answer::NxDomain].into_iter()),
+            Answer::NoData
+        ));
+        assert!(matches!(
+            Dns::combine_answers(vec![Answer::NxDomain].into_iter()),
+            Answer::NxDomain
+        ));
+    }
+}
@@ -1,49 +1,161 @@
 use std::{
     collections::HashMap,
     future::Future,
+    net::IpAddr,
     sync::{Arc, LazyLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::{Context, Result};
 use bollard::{
     container::ListContainersOptions, secret::ContainerSummary, system::EventsOptions,
-    Docker as DockerSocket,
+    Docker as DockerSocket, API_DEFAULT_VERSION,
 };
 use futures_util::stream::StreamExt;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use regex::Regex;
-use tokio::sync::Mutex;
+use tokio::{
+    sync::Mutex,
+    time::{sleep, sleep_until, Instant},
+};
 
-use crate::{constants::*, registry::Registry};
+use crate::{
+    constants::DEFAULT_DOCKER_SOCKET,
+    env::Env,
+    metrics::{docker_event_action, DOCKER_EVENTS, DOCKER_RECONNECTS},
+    registry::Registry,
+    watch::publish_changes,
+};
 
 static CADDY_LABEL_REGEX: LazyLock<Regex> =
     LazyLock::new(|| Regex::new(r"^caddy$|^caddy_\d+$").unwrap());
 static SNIPPET_VALUE_REGEX: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^\(.*\)$").unwrap());
-static PUBLIC_TLD_REGEX: LazyLock<Regex> = LazyLock::new(|| {
-    Regex::new(&format!(
-        r"(?:https?://)?(.*)\.{}(?::\d+)?$",
-        PUBLIC_SERVICE_TLD
-    ))
-    .unwrap()
-});
-static PRIVATE_TLD_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+static PUBLIC_TLD_REGEX: LazyLock<Regex> = LazyLock::new(|| tld_regex(&Env::public_service_tld()));
+static PRIVATE_TLD_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| tld_regex(&Env::private_service_tld()));
+
+fn tld_regex(tld: &str) -> Regex {
     Regex::new(&format!(
-        r"(?:https?://)?(.*)\.{}(?::\d+)?$",
-        PRIVATE_SERVICE_TLD
+        r"(?:(?P<scheme>https?)://)?(?P<service>.*)\.{}(?::(?P<port>\d+))?$",
+        regex::escape(tld)
     ))
     .unwrap()
-});
+}
+
+const REFRESH_ACTIONS: [&str; 8] = [
+    "start", "stop", "die", "kill", "destroy", "pause", "unpause", "rename",
+];
+
+const RECONNECT_MIN_BACKOFF: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+const DOCKER_TIMEOUT: u64 = 120;
+
+const DEBOUNCE_MAX_WAIT_WINDOWS: u32 = 10;
+
+struct Debounce {
+    window: Duration,
+    last_event: Option<Instant>,
+    pending_since: Option<Instant>,
+}
+
+impl Debounce {
+    fn new(window: Duration) -> Self {
+        Self {
+            window,
+            last_event: None,
+            pending_since: None,
+        }
+    }
+
+    fn event(&mut self, now: Instant) -> bool {
+        let idle = self.pending_since.is_none()
+            && self
+                .last_event
+                .is_none_or(|last_event| now >= last_event + self.window);
+        self.last_event = Some(now);
+        if !idle {
+            self.pending_since.get_or_insert(now);
+        }
+
+        idle
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        let pending_since = self.pending_since?;
+        let last_event = self.last_event.unwrap_or(pending_since);
+
+        Some(
+            (last_event + self.window).min(pending_since + self.window * DEBOUNCE_MAX_WAIT_WINDOWS),
+        )
+    }
+
+    fn fire(&mut self) {
+        self.pending_since = None;
+    }
+}
 
 pub struct Docker {
     pub socket: DockerSocket,
+    endpoint: String,
 }
 
 impl Docker {
-    pub fn new() -> Result<Self> {
-        let socket = DockerSocket::connect_with_unix_defaults()
-            .context("Failed to connect to Docker socket.")?;
+    pub async fn new() -> Result<Self> {
+        let endpoint = match (Env::docker_socket(), Env::docker_host()) {
+            (Some(path), _) => format!("unix://{}", path),
+            (None, Some(host)) => host,
+            (None, None) => format!("unix://{}", DEFAULT_DOCKER_SOCKET),
+        };
+
+        info!("Connecting to Docker at `{}`.", endpoint);
+        let socket = Self::connect(&endpoint)
+            .context(format!("Failed to connect to Docker at `{}`.", endpoint))?;
+
+        let msg = if Self::uses_tls(&endpoint) {
+            format!(
+                "Failed to reach Docker at `{}` with TLS certificates from `{}`.",
+                endpoint,
+                Env::docker_cert_path()?.display()
+            )
+        } else {
+            format!("Failed to reach Docker at `{}`.", endpoint)
+        };
+        socket.ping().await.context(msg)?;
+
+        Ok(Self { socket, endpoint })
+    }
 
-        Ok(Self { socket })
+    fn uses_tls(host: &str) -> bool {
+        !host.starts_with("unix://") && (host.starts_with("https://") || Env::docker_tls_verify())
+    }
+
+    fn connect(host: &str) -> Result<DockerSocket> {
+        if host.starts_with("unix://") {
+            return Ok(DockerSocket::connect_with_unix(
+                host,
+                DOCKER_TIMEOUT,
+                API_DEFAULT_VERSION,
+            )?);
+        }
+
+        if Self::uses_tls(host) {
+            let cert_path = Env::docker_cert_path()?;
+            return Ok(DockerSocket::connect_with_ssl(
+                host,
+                &cert_path.join("key.pem"),
+                &cert_path.join("cert.pem"),
+                &cert_path.join("ca.pem"),
+                DOCKER_TIMEOUT,
+                API_DEFAULT_VERSION,
+            )?);
+        }
+
+        Ok(DockerSocket::connect_with_http(
+            host,
+            DOCKER_TIMEOUT,
+            API_DEFAULT_VERSION,
+        )?)
     }
 
     async fn list_running_containers(&self) -> Result<Vec<ContainerSummary>> {
@@ -54,7 +166,10 @@ impl Docker {
                 ..Default::default()
             }))
             .await
-            .context(format!("Failed to list running containers."))
+            .context(format!(
+                "Failed to list running containers from Docker at `{}`.",
+                self.endpoint
+            ))
     }
 
     fn get_container_name(container: &ContainerSummary) -> String {
@@ -62,7 +177,7 @@ impl Docker {
             .names
             .clone()
             .map_or("unknown".to_string(), |names| {
-                names.get(0).map_or("unknown".to_string(), |name| {
+                names.first().map_or("unknown".to_string(), |name| {
                     name.trim_start_matches('/').to_string()
                 })
             })
@@ -83,6 +198,28 @@ impl Docker {
             })
     }
 
+    fn get_network_addresses(container: &ContainerSummary) -> Vec<(String, IpAddr)> {
+        let networks = container
+            .network_settings
+            .clone()
+            .and_then(|settings| settings.networks)
+            .unwrap_or_default();
+
+        let mut addresses = vec![];
+        for (network, endpoint) in networks {
+            for address in [endpoint.ip_address, endpoint.global_ipv6_address]
+                .into_iter()
+                .flatten()
+            {
+                if let Ok(address) = address.parse() {
+                    addresses.push((network.clone(), address));
+                }
+            }
+        }
+
+        addresses
+    }
+
     fn parse_address(address: &str) -> Vec<String> {
         let mut list = vec![];
 
@@ -101,27 +238,67 @@ impl Docker {
     fn capture_service(address: &str, regex: &Regex) -> Option<String> {
         regex
             .captures(address)
-            .map(|captures| captures.get(1).unwrap().as_str().to_string())
+            .map(|captures| captures["service"].to_string())
+    }
+
+    fn capture_port(address: &str, regex: &Regex) -> Option<u16> {
+        let captures = regex.captures(address)?;
+
+        match (captures.name("scheme"), captures.name("port")) {
+            (_, Some(port)) => port.as_str().parse().ok(),
+            (Some(scheme), None) if scheme.as_str() == "http" => Some(80),
+            _ => Some(443),
+        }
     }
 
     pub async fn flush_registry_services(&self, registry: Arc<Mutex<Registry>>) {
+        info!("Flushing services for self registry.");
+        let containers = match self.list_running_containers().await {
+            Ok(containers) => containers,
+            Err(err) => {
+                error!("{}", err);
+                return;
+            }
+        };
+
         let mut registry = registry.lock().await;
+        let previous = registry.clone();
         registry.clear_public_services();
         registry.clear_private_services();
+        registry.clear_service_addresses();
 
-        let mut process_address = |address: &String| {
-            if let Some(service) = Self::capture_service(address, &PUBLIC_TLD_REGEX) {
+        let record_addresses = Env::service_network().is_some();
+        let mut process_address = |address: &String, network_addresses: &[(String, IpAddr)]| {
+            let service = if let Some(service) = Self::capture_service(address, &PUBLIC_TLD_REGEX) {
+                let port = Self::capture_port(address, &PUBLIC_TLD_REGEX);
                 debug!(
-                    "Captured public service `{}` from address `{}`",
-                    service, address
+                    "Captured public service `{}` with port `{:?}` from address `{}`",
+                    service, port, address
                 );
-                registry.add_public_service(service);
+                registry.add_public_service(service.clone(), port);
+
+                service
             } else if let Some(service) = Self::capture_service(address, &PRIVATE_TLD_REGEX) {
+                let port = Self::capture_port(address, &PRIVATE_TLD_REGEX);
                 debug!(
-                    "Captured private service `{}` from address `{}`",
-                    service, address
+                    "Captured private service `{}` with port `{:?}` from address `{}`",
+                    service, port, address
                 );
-                registry.add_private_service(service);
+                registry.add_private_service(service.clone(), port);
+
+                service
+            } else {
+                return;
+            };
+
+            if record_addresses {
+                for (network, network_address) in network_addresses {
+                    registry.add_service_address(
+                        service.clone(),
+                        network.clone(),
+                        *network_address,
+                    );
+                }
             }
         };
 
@@ -133,33 +310,35 @@ impl Docker {
                 values
             );
 
+            let network_addresses = Self::get_network_addresses(&container);
+            debug!(
+                "Found network addresses for container `{}`: {:?}",
+                Self::get_container_name(&container),
+                network_addresses
+            );
+
             for value in values {
                 for address in Self::parse_address(&value) {
-                    process_address(&address);
+                    process_address(&address, &network_addresses);
                 }
             }
         };
 
-        info!("Flushing services for self registry.",);
-        match self.list_running_containers().await {
-            Ok(containers) => {
-                for container in containers {
-                    process_container(container);
-                }
-
-                info!(
-                    "Flushed public services for self registry: {:?}",
-                    registry.public_services()
-                );
-                info!(
-                    "Flushed private services for self registry: {:?}",
-                    registry.private_services()
-                );
-            }
-            Err(err) => {
-                error!("{}", err);
-            }
+        for container in containers {
+            process_container(container);
         }
+
+        info!(
+            "Flushed public services for self registry: {:?}",
+            registry.public_services()
+        );
+        info!(
+            "Flushed private services for self registry: {:?}",
+            registry.private_services()
+        );
+
+        registry.commit_public_services(previous.public_services());
+        publish_changes(&previous, &registry);
     }
 
     pub async fn watch_events<F, Fut>(&self, callback: F)
@@ -167,27 +346,83 @@ impl Docker {
         F: Fn() -> Fut + Send,
         Fut: Future<Output = ()> + Send,
     {
-        let mut events = self.socket.events(Some(EventsOptions {
-            filters: HashMap::from_iter(vec![("type", vec!["container"])]),
-            ..Default::default()
-        }));
-
-        while let Some(event) = events.next().await {
-            let action = event
-                .map_err(|err| {
-                    error!("Failed to watch Docker events.\nError: {}", err);
-                    err
-                })
-                .map(|event| event.action)
-                .ok()
-                .flatten();
-
-            if let Some(action) = action {
-                if action == "start" {
-                    info!("Detected container start event.");
-                    callback().await;
+        let mut backoff = RECONNECT_MIN_BACKOFF;
+        let mut since: Option<String> = None;
+
+        loop {
+            // The stream only connects on its first poll, after the refresh below, so changes
+            // while re-listing are covered by replaying events since the disconnect, not by ordering.
+            let mut events = self.socket.events(Some(EventsOptions {
+                since: since.clone(),
+                filters: HashMap::from_iter(vec![("type", vec!["container"])]),
+                ..Default::default()
+            }));
+            if since.is_some() {
+                callback().await;
+            }
+
+            let mut debounce = Debounce::new(Env::docker_events_debounce());
+
+            loop {
+                let deadline = debounce.deadline();
+                let event = tokio::select! {
+                    event = events.next() => event,
+                    _ = sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                        debounce.fire();
+                        info!("Refreshing after a burst of container events.");
+                        callback().await;
+                        continue;
+                    }
+                };
+
+                let action = match event {
+                    Some(Ok(event)) => event.action,
+                    Some(Err(err)) => {
+                        error!(
+                            "Failed to watch Docker events from `{}`.\nError: {}",
+                            self.endpoint, err
+                        );
+                        break;
+                    }
+                    None => break,
+                };
+                backoff = RECONNECT_MIN_BACKOFF;
+
+                if let Some(action) = action {
+                    DOCKER_EVENTS
+                        .with_label_values(&[docker_event_action(&action)])
+                        .inc();
+
+                    if REFRESH_ACTIONS.contains(&action.as_str()) {
+                        info!("Detected container {} event.", action);
+                        if debounce.event(Instant::now()) {
+                            callback().await;
+                        } else {
+                            debug!("Deferring refresh until container events settle.");
+                        }
+                    }
                 }
             }
+
+            let disconnected = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            since = Some(format!(
+                "{}.{:09}",
+                disconnected.as_secs(),
+                disconnected.subsec_nanos()
+            ));
+
+            warn!("Docker event stream ended. Reconnecting in {:?}.", backoff);
+            sleep(backoff).await;
+            backoff = (backoff * 2).min(RECONNECT_MAX_BACKOFF);
+
+            DOCKER_RECONNECTS.inc();
+            let reconnects = DOCKER_RECONNECTS.get();
+            info!(
+                "Reconnecting to Docker event stream (reconnects: {}).",
+                reconnects
+            );
         }
     }
 }
@@ -234,12 +469,12 @@ mod tests {
         // Test with PUBLIC_TLD_REGEX matches.
         let public_tests = vec![
             // Assuming PUBLIC_SERVICE_TLD is "public", for example "service.public" should capture "service"
-            ("service.pub", "service"),
-            ("another-service.pub:8080", "another-service"),
-            ("sub.domain.pub", "sub.domain"),
-            ("http://service.pub", "service"),
-            ("http://another-service.pub:8080", "another-service"),
-            ("http://sub.domain.pub", "sub.domain"),
+            ("service.public", "service"),
+            ("another-service.public:8080", "another-service"),
+            ("sub.domain.public", "sub.domain"),
+            ("http://service.public", "service"),
+            ("http://another-service.public:8080", "another-service"),
+            ("http://sub.domain.public", "sub.domain"),
         ];
         for (input, expected) in public_tests {
             let result = Docker::capture_service(input, &PUBLIC_TLD_REGEX);
@@ -254,12 +489,12 @@ mod tests {
         // Test with PRIVATE_TLD_REGEX matches.
         let private_tests = vec![
             // Assuming PRIVATE_SERVICE_TLD is "private", for example "service.private" should capture "service"
-            ("service.priv", "service"),
-            ("another-service.priv:3000", "another-service"),
-            ("sub.domain.priv", "sub.domain"),
-            ("http://service.priv", "service"),
-            ("http://another-service.priv:3000", "another-service"),
-            ("http://sub.domain.priv", "sub.domain"),
+            ("service.private", "service"),
+            ("another-service.private:3000", "another-service"),
+            ("sub.domain.private", "sub.domain"),
+            ("http://service.private", "service"),
+            ("http://another-service.private:3000", "another-service"),
+            ("http://sub.domain.private", "sub.domain"),
         ];
         for (input, expected) in private_tests {
             let result = Docker::capture_service(input, &PRIVATE_TLD_REGEX);
@@ -273,11 +508,11 @@ mod tests {
 
         // Test non-matching strings return None.
         let non_matching = vec![
-            "something.pubx",
-            "something.privx",
+            "something.publicx",
+            "something.privatex",
             "http://",
-            "http://something.pubx",
-            "http://something.privx",
+            "http://something.publicx",
+            "http://something.privatex",
             "no-tld-here",
             "service.unknown:1234",
             "127.0.0.1",
@@ -293,4 +528,85 @@ mod tests {
             );
         }
     }
+
+    #[tokio::test]
+    async fn test_capture_port() {
+        let test_cases = vec![
+            ("service.public", Some(443)),
+            ("service.public:8443", Some(8443)),
+            ("http://service.public", Some(80)),
+            ("https://service.public", Some(443)),
+            ("http://service.public:8080", Some(8080)),
+            ("service.public:99999", None),
+            ("service.unknown:1234", None),
+        ];
+
+        for (input, expected) in test_cases {
+            let result = Docker::capture_port(input, &PUBLIC_TLD_REGEX);
+            assert_eq!(result, expected, "Failed for input: {:?}", input);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_capture_service_with_multi_label_tld() {
+        let regex = tld_regex("svc.corp");
+        let test_cases = vec![
+            ("service.svc.corp", Some("service")),
+            ("http://sub.service.svc.corp:8080", Some("sub.service")),
+            ("service.svcxcorp", None),
+            ("service.corp", None),
+        ];
+
+        for (input, expected) in test_cases {
+            let result = Docker::capture_service(input, &regex);
+            assert_eq!(
+                result,
+                expected.map(String::from),
+                "Failed for input: {:?}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_debounce() {
+        let window = Duration::from_millis(500);
+        let start = Instant::now();
+        let mut debounce = Debounce::new(window);
+
+        // first event while idle is handled right away
+        assert!(debounce.event(start));
+        assert_eq!(debounce.deadline(), None);
+
+        // events within the window are deferred and extend the deadline
+        assert!(!debounce.event(start + Duration::from_millis(100)));
+        assert!(!debounce.event(start + Duration::from_millis(300)));
+        assert_eq!(
+            debounce.deadline(),
+            Some(start + Duration::from_millis(800))
+        );
+
+        // an event after the window is still deferred while a refresh is pending
+        assert!(!debounce.event(start + Duration::from_millis(900)));
+        assert_eq!(
+            debounce.deadline(),
+            Some(start + Duration::from_millis(1400))
+        );
+
+        debounce.fire();
+        assert_eq!(debounce.deadline(), None);
+
+        // after the window has passed without events, the next one is handled right away
+        assert!(debounce.event(start + Duration::from_millis(2000)));
+        assert_eq!(debounce.deadline(), None);
+
+        // steady events can't defer the refresh past the max wait
+        for millis in (2300..8000).step_by(300) {
+            assert!(!debounce.event(start + Duration::from_millis(millis)));
+        }
+        assert_eq!(
+            debounce.deadline(),
+            Some(start + Duration::from_millis(7300))
+        );
+    }
 }
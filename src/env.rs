@@ -1,11 +1,14 @@
-use std::{env::var, error, fmt, net::SocketAddr, str::FromStr, sync::LazyLock};
+use std::{
+    env::var, error, fmt, fs::read_to_string, net::SocketAddr, path::PathBuf, str::FromStr,
+    sync::LazyLock, time::Duration,
+};
 
 use anyhow::{anyhow, Context, Result};
 use hickory_server::proto::rr::Name;
 use log::debug;
 use reqwest::Url;
 
-use crate::{constants::*, registry::Registry};
+use crate::{conflict::ConflictPolicy, constants::*, registry::Registry};
 
 fn create_error_msg(key: &str, value: &str) -> String {
     format!(
@@ -14,21 +17,58 @@ fn create_error_msg(key: &str, value: &str) -> String {
     )
 }
 
+const SECRET_ENVS: [&str; 2] = [REGISTRY_TOKEN_ENV, REGISTRY_TLS_KEY_ENV];
+
+fn display_value<'a>(key: &str, value: &'a str) -> &'a str {
+    if SECRET_ENVS.contains(&key) {
+        "<redacted>"
+    } else {
+        value
+    }
+}
+
+fn get_env(key: &str) -> Result<Option<String>> {
+    if let Ok(value) = var(key) {
+        return Ok(Some(value));
+    }
+
+    let file_key = format!("{}_FILE", key);
+    match var(&file_key) {
+        Ok(path) => {
+            debug!(
+                "Environment variable `{}` found with value `{}`.",
+                file_key, path
+            );
+            let value = read_to_string(&path).context(format!(
+                "Failed to read file `{}` from environment variable `{}`.",
+                path, file_key
+            ))?;
+
+            Ok(Some(value.trim().to_string()))
+        }
+
+        Err(_) => Ok(None),
+    }
+}
+
 fn get_parsed_env<T>(key: &str, default_value: Option<&str>) -> Result<T>
 where
     T: FromStr,
     <T as FromStr>::Err: fmt::Display + fmt::Debug + error::Error + Send + Sync + 'static,
 {
-    match var(key) {
-        Ok(value) => {
+    match get_env(key)? {
+        Some(value) => {
             debug!(
                 "Environment variable `{}` found with value `{}`.",
-                key, value
+                key,
+                display_value(key, &value)
             );
-            value.parse::<T>().context(create_error_msg(key, &value))
+            value
+                .parse::<T>()
+                .context(create_error_msg(key, display_value(key, &value)))
         }
 
-        Err(_) => {
+        None => {
             if let Some(default_value) = default_value {
                 debug!(
                     "Environment variable `{}` not found. Using default value `{}`.",
@@ -44,26 +84,105 @@ where
 
 static SERVER_LISTEN: LazyLock<Result<SocketAddr>> =
     LazyLock::new(|| get_parsed_env(SERVER_LISTEN_ENV, Some(DEFAULT_SERVER_LISTEN)));
+static SERVER_TCP_TIMEOUT: LazyLock<Result<u64>> =
+    LazyLock::new(|| get_parsed_env(SERVER_TCP_TIMEOUT_ENV, Some(DEFAULT_SERVER_TCP_TIMEOUT)));
 static REGISTRY_LISTEN: LazyLock<Result<SocketAddr>> =
     LazyLock::new(|| get_parsed_env(REGISTRY_LISTEN_ENV, Some(DEFAULT_REGISTRY_LISTEN)));
 static SELF_HOSTNAME: LazyLock<Result<Name>> =
     LazyLock::new(|| get_parsed_env(REGISTRY_HOSTNAME_ENV, None));
 static REGISTRY_URLS: LazyLock<Result<String>> =
     LazyLock::new(|| get_parsed_env(REGISTRY_URLS_ENV, Some(Default::default())));
+static REGISTRY_GOSSIP_HOPS: LazyLock<Result<u32>> =
+    LazyLock::new(|| get_parsed_env(REGISTRY_GOSSIP_HOPS_ENV, Some(DEFAULT_REGISTRY_GOSSIP_HOPS)));
+static REGISTRY_MAX_PEERS: LazyLock<Result<usize>> =
+    LazyLock::new(|| get_parsed_env(REGISTRY_MAX_PEERS_ENV, Some(DEFAULT_REGISTRY_MAX_PEERS)));
+static REGISTRY_TOKEN: LazyLock<Result<String>> =
+    LazyLock::new(|| get_parsed_env(REGISTRY_TOKEN_ENV, Some(Default::default())));
+static REGISTRY_TLS_CERT: LazyLock<Result<String>> =
+    LazyLock::new(|| get_parsed_env(REGISTRY_TLS_CERT_ENV, Some(Default::default())));
+static REGISTRY_TLS_KEY: LazyLock<Result<String>> =
+    LazyLock::new(|| get_parsed_env(REGISTRY_TLS_KEY_ENV, Some(Default::default())));
+static REGISTRY_TLS_CA: LazyLock<Result<String>> =
+    LazyLock::new(|| get_parsed_env(REGISTRY_TLS_CA_ENV, Some(Default::default())));
+static REGISTRY_REQUEST_TIMEOUT: LazyLock<Result<u64>> = LazyLock::new(|| {
+    get_parsed_env(
+        REGISTRY_REQUEST_TIMEOUT_ENV,
+        Some(DEFAULT_REGISTRY_REQUEST_TIMEOUT),
+    )
+});
+static REGISTRY_SYNC_INTERVAL: LazyLock<Result<u64>> = LazyLock::new(|| {
+    get_parsed_env(
+        REGISTRY_SYNC_INTERVAL_ENV,
+        Some(DEFAULT_REGISTRY_SYNC_INTERVAL),
+    )
+});
+static REGISTRY_TTL: LazyLock<Result<u64>> =
+    LazyLock::new(|| get_parsed_env(REGISTRY_TTL_ENV, Some(DEFAULT_REGISTRY_TTL)));
+static DOCKER_HOST: LazyLock<Result<String>> =
+    LazyLock::new(|| get_parsed_env(DOCKER_HOST_ENV, Some(Default::default())));
+static DOCKER_TLS_VERIFY: LazyLock<Result<String>> =
+    LazyLock::new(|| get_parsed_env(DOCKER_TLS_VERIFY_ENV, Some(Default::default())));
+static DOCKER_CERT_PATH: LazyLock<Result<String>> =
+    LazyLock::new(|| get_parsed_env(DOCKER_CERT_PATH_ENV, Some(Default::default())));
+static DOCKER_SOCKET: LazyLock<Result<String>> =
+    LazyLock::new(|| get_parsed_env(DOCKER_SOCKET_ENV, Some(Default::default())));
+static DOCKER_EVENTS_DEBOUNCE: LazyLock<Result<u64>> = LazyLock::new(|| {
+    get_parsed_env(
+        DOCKER_EVENTS_DEBOUNCE_ENV,
+        Some(DEFAULT_DOCKER_EVENTS_DEBOUNCE),
+    )
+});
+static SERVICE_NETWORK: LazyLock<Result<String>> =
+    LazyLock::new(|| get_parsed_env(SERVICE_NETWORK_ENV, Some(Default::default())));
+static SERVICE_CONFLICT_POLICY: LazyLock<Result<String>> = LazyLock::new(|| {
+    get_parsed_env(
+        SERVICE_CONFLICT_POLICY_ENV,
+        Some(DEFAULT_SERVICE_CONFLICT_POLICY),
+    )
+});
+static SERVICE_PRIORITY: LazyLock<Result<String>> =
+    LazyLock::new(|| get_parsed_env(SERVICE_PRIORITY_ENV, Some(Default::default())));
+static PUBLIC_SERVICE_TLD: LazyLock<Result<Name>> =
+    LazyLock::new(|| get_parsed_env(PUBLIC_SERVICE_TLD_ENV, Some(DEFAULT_PUBLIC_SERVICE_TLD)));
+static PRIVATE_SERVICE_TLD: LazyLock<Result<Name>> =
+    LazyLock::new(|| get_parsed_env(PRIVATE_SERVICE_TLD_ENV, Some(DEFAULT_PRIVATE_SERVICE_TLD)));
+
+fn normalize_tld(key: &str, tld: &Name) -> Result<String> {
+    if tld.is_root() {
+        return Err(anyhow!("Environment variable `{}` must not be empty.", key));
+    }
+
+    Ok(tld
+        .to_lowercase()
+        .to_string()
+        .trim_end_matches('.')
+        .to_string())
+}
 
 pub struct Env {}
 
 impl Env {
     fn get_server_listen() -> Result<SocketAddr> {
         match &*SERVER_LISTEN {
-            Ok(server_listen) => Ok(server_listen.clone()),
+            Ok(server_listen) => Ok(*server_listen),
+            Err(err) => Err(anyhow!("{}", err)),
+        }
+    }
+
+    fn get_server_tcp_timeout() -> Result<Duration> {
+        match &*SERVER_TCP_TIMEOUT {
+            Ok(0) => Err(anyhow!(
+                "Environment variable `{}` must be at least `1`.",
+                SERVER_TCP_TIMEOUT_ENV
+            )),
+            Ok(server_tcp_timeout) => Ok(Duration::from_secs(*server_tcp_timeout)),
             Err(err) => Err(anyhow!("{}", err)),
         }
     }
 
     fn get_registry_listen() -> Result<SocketAddr> {
         match &*REGISTRY_LISTEN {
-            Ok(registry_listen) => Ok(registry_listen.clone()),
+            Ok(registry_listen) => Ok(*registry_listen),
             Err(err) => Err(anyhow!("{}", err)),
         }
     }
@@ -73,9 +192,21 @@ impl Env {
             Ok(self_hostname) => Ok(self_hostname.clone()),
             Err(err) => Err(anyhow!("{}", err)),
         }?;
-        let url = Url::parse(&format!("http://{}", Self::get_registry_listen()?))?;
+        let scheme = match Self::get_registry_tls_cert()? {
+            Some(_) => "https",
+            None => "http",
+        };
+        let url = Url::parse(&format!(
+            "{}://{}:{}",
+            scheme,
+            hostname.to_string().trim_end_matches('.'),
+            Self::get_registry_listen()?.port()
+        ))?;
+
+        let mut registry = Registry::new(hostname, url);
+        registry.start_epoch();
 
-        Ok(Registry::new(hostname, url))
+        Ok(registry)
     }
 
     fn get_registries() -> Result<Vec<Registry>> {
@@ -85,18 +216,247 @@ impl Env {
         }?;
 
         let mut registries = vec![];
-        for url in urls.split(" ") {
+        for url in urls.split_whitespace() {
             registries.push(url.parse()?);
         }
 
         Ok(registries)
     }
 
+    fn get_registry_gossip_hops() -> Result<Option<u32>> {
+        match &*REGISTRY_GOSSIP_HOPS {
+            Ok(0) => Ok(None),
+            Ok(hops) => Ok(Some(*hops)),
+            Err(err) => Err(anyhow!("{}", err)),
+        }
+    }
+
+    fn get_registry_max_peers() -> Result<Option<usize>> {
+        match &*REGISTRY_MAX_PEERS {
+            Ok(0) => Ok(None),
+            Ok(max_peers) => Ok(Some(*max_peers)),
+            Err(err) => Err(anyhow!("{}", err)),
+        }
+    }
+
+    fn get_public_service_tld() -> Result<String> {
+        match &*PUBLIC_SERVICE_TLD {
+            Ok(tld) => normalize_tld(PUBLIC_SERVICE_TLD_ENV, tld),
+            Err(err) => Err(anyhow!("{}", err)),
+        }
+    }
+
+    fn get_private_service_tld() -> Result<String> {
+        match &*PRIVATE_SERVICE_TLD {
+            Ok(tld) => normalize_tld(PRIVATE_SERVICE_TLD_ENV, tld),
+            Err(err) => Err(anyhow!("{}", err)),
+        }
+    }
+
+    fn get_registry_token() -> Result<Option<String>> {
+        match &*REGISTRY_TOKEN {
+            Ok(token) if token.is_empty() => Ok(None),
+            Ok(token) => Ok(Some(token.clone())),
+            Err(err) => Err(anyhow!("{}", err)),
+        }
+    }
+
+    fn get_registry_tls_cert() -> Result<Option<String>> {
+        match &*REGISTRY_TLS_CERT {
+            Ok(cert) if cert.is_empty() => Ok(None),
+            Ok(cert) => Ok(Some(cert.clone())),
+            Err(err) => Err(anyhow!("{}", err)),
+        }
+    }
+
+    fn get_registry_tls_key() -> Result<Option<String>> {
+        match &*REGISTRY_TLS_KEY {
+            Ok(key) if key.is_empty() => Ok(None),
+            Ok(key) => Ok(Some(key.clone())),
+            Err(err) => Err(anyhow!("{}", err)),
+        }
+    }
+
+    fn get_registry_tls_ca() -> Result<Option<String>> {
+        match &*REGISTRY_TLS_CA {
+            Ok(ca) if ca.is_empty() => Ok(None),
+            Ok(ca) => Ok(Some(ca.clone())),
+            Err(err) => Err(anyhow!("{}", err)),
+        }
+    }
+
+    fn validate_registry_tls() -> Result<()> {
+        match (
+            Self::get_registry_tls_cert()?,
+            Self::get_registry_tls_key()?,
+        ) {
+            (Some(_), None) | (None, Some(_)) => Err(anyhow!(
+                "Environment variables `{}` and `{}` must be set together.",
+                REGISTRY_TLS_CERT_ENV,
+                REGISTRY_TLS_KEY_ENV
+            )),
+            _ => {
+                Self::get_registry_tls_ca()?;
+
+                Ok(())
+            }
+        }
+    }
+
+    fn get_registry_request_timeout() -> Result<Duration> {
+        match &*REGISTRY_REQUEST_TIMEOUT {
+            Ok(0) => Err(anyhow!(
+                "Environment variable `{}` must be at least `1`.",
+                REGISTRY_REQUEST_TIMEOUT_ENV
+            )),
+            Ok(timeout) => Ok(Duration::from_secs(*timeout)),
+            Err(err) => Err(anyhow!("{}", err)),
+        }
+    }
+
+    fn get_registry_sync_interval() -> Result<Option<Duration>> {
+        match &*REGISTRY_SYNC_INTERVAL {
+            Ok(0) => Ok(None),
+            Ok(interval) => Ok(Some(Duration::from_secs(*interval))),
+            Err(err) => Err(anyhow!("{}", err)),
+        }
+    }
+
+    fn get_registry_ttl() -> Result<Option<Duration>> {
+        match &*REGISTRY_TTL {
+            Ok(0) => Ok(None),
+            Ok(ttl) => Ok(Some(Duration::from_secs(*ttl))),
+            Err(err) => Err(anyhow!("{}", err)),
+        }
+    }
+
+    fn get_docker_host() -> Result<Option<String>> {
+        match &*DOCKER_HOST {
+            Ok(host) if host.is_empty() => Ok(None),
+            Ok(host)
+                if ["unix://", "tcp://", "http://", "https://"]
+                    .iter()
+                    .any(|scheme| host.starts_with(scheme)) =>
+            {
+                Ok(Some(host.clone()))
+            }
+            Ok(host) => Err(anyhow!(
+                "Unsupported Docker host `{}` in environment variable `{}`, expected `unix://`, `tcp://`, `http://` or `https://`.",
+                host,
+                DOCKER_HOST_ENV
+            )),
+            Err(err) => Err(anyhow!("{}", err)),
+        }
+    }
+
+    fn get_docker_tls_verify() -> Result<bool> {
+        match &*DOCKER_TLS_VERIFY {
+            Ok(verify) => Ok(!matches!(verify.trim(), "" | "0")),
+            Err(err) => Err(anyhow!("{}", err)),
+        }
+    }
+
+    fn get_docker_cert_path() -> Result<PathBuf> {
+        match &*DOCKER_CERT_PATH {
+            Ok(path) if path.is_empty() => var("HOME")
+                .map(|home| PathBuf::from(home).join(".docker"))
+                .context(format!(
+                    "Failed to find Docker certificates, set environment variable `{}`.",
+                    DOCKER_CERT_PATH_ENV
+                )),
+            Ok(path) => Ok(PathBuf::from(path)),
+            Err(err) => Err(anyhow!("{}", err)),
+        }
+    }
+
+    fn get_docker_socket() -> Result<Option<String>> {
+        match &*DOCKER_SOCKET {
+            Ok(socket) if socket.is_empty() => Ok(None),
+            Ok(socket) => Ok(Some(socket.trim_start_matches("unix://").to_string())),
+            Err(err) => Err(anyhow!("{}", err)),
+        }
+    }
+
+    fn get_docker_events_debounce() -> Result<Duration> {
+        match &*DOCKER_EVENTS_DEBOUNCE {
+            Ok(debounce) => Ok(Duration::from_millis(*debounce)),
+            Err(err) => Err(anyhow!("{}", err)),
+        }
+    }
+
+    fn get_service_network() -> Result<Option<String>> {
+        match &*SERVICE_NETWORK {
+            Ok(network) if network.is_empty() => Ok(None),
+            Ok(network) => Ok(Some(network.clone())),
+            Err(err) => Err(anyhow!("{}", err)),
+        }
+    }
+
+    fn get_service_conflict_policy() -> Result<ConflictPolicy> {
+        match &*SERVICE_CONFLICT_POLICY {
+            Ok(policy) => policy
+                .parse()
+                .context(create_error_msg(SERVICE_CONFLICT_POLICY_ENV, policy)),
+            Err(err) => Err(anyhow!("{}", err)),
+        }
+    }
+
+    fn get_service_priority() -> Result<Vec<Name>> {
+        let hostnames = match &*SERVICE_PRIORITY {
+            Ok(hostnames) => Ok(hostnames.clone()),
+            Err(err) => Err(anyhow!("{}", err)),
+        }?;
+
+        let mut priority = vec![];
+        for hostname in hostnames.split_whitespace() {
+            priority.push(
+                hostname
+                    .parse()
+                    .context(create_error_msg(SERVICE_PRIORITY_ENV, hostname))?,
+            );
+        }
+
+        Ok(priority)
+    }
+
+    fn validate_service_tlds() -> Result<()> {
+        let public_tld = Self::get_public_service_tld()?;
+        let private_tld = Self::get_private_service_tld()?;
+
+        let is_suffix =
+            |tld: &str, other: &str| tld == other || tld.ends_with(&format!(".{}", other));
+        if is_suffix(&public_tld, &private_tld) || is_suffix(&private_tld, &public_tld) {
+            return Err(anyhow!(
+                "Public service TLD `{}` and private service TLD `{}` must not overlap.",
+                public_tld,
+                private_tld
+            ));
+        }
+
+        Ok(())
+    }
+
     pub fn validate() -> Result<()> {
         Self::get_server_listen()?;
+        Self::get_server_tcp_timeout()?;
         Self::get_registry_listen()?;
         Self::get_self_registry()?;
         Self::get_registries()?;
+        Self::get_registry_gossip_hops()?;
+        Self::get_registry_max_peers()?;
+        Self::get_registry_token()?;
+        Self::validate_registry_tls()?;
+        Self::get_registry_request_timeout()?;
+        Self::get_registry_sync_interval()?;
+        Self::get_registry_ttl()?;
+        Self::validate_service_tlds()?;
+        Self::get_service_network()?;
+        Self::get_service_conflict_policy()?;
+        Self::get_service_priority()?;
+        Self::get_docker_host()?;
+        Self::get_docker_tls_verify()?;
+        Self::get_docker_socket()?;
+        Self::get_docker_events_debounce()?;
 
         Ok(())
     }
@@ -105,6 +465,10 @@ impl Env {
         Self::get_server_listen().unwrap()
     }
 
+    pub fn server_tcp_timeout() -> Duration {
+        Self::get_server_tcp_timeout().unwrap()
+    }
+
     pub fn registry_listen() -> SocketAddr {
         Self::get_registry_listen().unwrap()
     }
@@ -116,4 +480,80 @@ impl Env {
     pub fn registries() -> Vec<Registry> {
         Self::get_registries().unwrap()
     }
+
+    pub fn registry_gossip_hops() -> Option<u32> {
+        Self::get_registry_gossip_hops().unwrap()
+    }
+
+    pub fn registry_max_peers() -> Option<usize> {
+        Self::get_registry_max_peers().unwrap()
+    }
+
+    pub fn public_service_tld() -> String {
+        Self::get_public_service_tld().unwrap()
+    }
+
+    pub fn private_service_tld() -> String {
+        Self::get_private_service_tld().unwrap()
+    }
+
+    pub fn service_network() -> Option<String> {
+        Self::get_service_network().unwrap()
+    }
+
+    pub fn service_conflict_policy() -> ConflictPolicy {
+        Self::get_service_conflict_policy().unwrap()
+    }
+
+    pub fn service_priority() -> Vec<Name> {
+        Self::get_service_priority().unwrap()
+    }
+
+    pub fn registry_token() -> Option<String> {
+        Self::get_registry_token().unwrap()
+    }
+
+    pub fn registry_tls_cert() -> Option<String> {
+        Self::get_registry_tls_cert().unwrap()
+    }
+
+    pub fn registry_tls_key() -> Option<String> {
+        Self::get_registry_tls_key().unwrap()
+    }
+
+    pub fn registry_tls_ca() -> Option<String> {
+        Self::get_registry_tls_ca().unwrap()
+    }
+
+    pub fn registry_request_timeout() -> Duration {
+        Self::get_registry_request_timeout().unwrap()
+    }
+
+    pub fn registry_sync_interval() -> Option<Duration> {
+        Self::get_registry_sync_interval().unwrap()
+    }
+
+    pub fn registry_ttl() -> Option<Duration> {
+        Self::get_registry_ttl().unwrap()
+    }
+
+    pub fn docker_host() -> Option<String> {
+        Self::get_docker_host().unwrap()
+    }
+
+    pub fn docker_tls_verify() -> bool {
+        Self::get_docker_tls_verify().unwrap()
+    }
+
+    pub fn docker_cert_path() -> Result<PathBuf> {
+        Self::get_docker_cert_path()
+    }
+
+    pub fn docker_socket() -> Option<String> {
+        Self::get_docker_socket().unwrap()
+    }
+
+    pub fn docker_events_debounce() -> Duration {
+        Self::get_docker_events_debounce().unwrap()
+    }
 }
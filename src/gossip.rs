@@ -0,0 +1,285 @@
+use std::{
+    collections::HashSet,
+    sync::{LazyLock, Mutex},
+};
+
+use hickory_server::proto::rr::Name;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::registry::Registry;
+
+static FORGOTTEN: LazyLock<Mutex<HashSet<Name>>> = LazyLock::new(Default::default);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Peer {
+    pub hostname: String,
+    pub url: String,
+    pub hops: u32,
+}
+
+#[derive(Debug, Default)]
+pub struct Membership {
+    pub added: Vec<Registry>,
+    pub removed: Vec<Registry>,
+}
+
+pub fn forget(hostname: &Name) {
+    FORGOTTEN.lock().unwrap().insert(hostname.clone());
+}
+
+pub fn remember(hostname: &Name) {
+    FORGOTTEN.lock().unwrap().remove(hostname);
+}
+
+fn is_forgotten(hostname: &Name) -> bool {
+    FORGOTTEN.lock().unwrap().contains(hostname)
+}
+
+pub fn peers(registries: &[Registry]) -> Vec<Peer> {
+    registries
+        .iter()
+        .filter(|registry| registry.is_reachable())
+        .map(|registry| Peer {
+            hostname: registry.hostname().to_string(),
+            url: registry.url().to_string(),
+            hops: registry.hops() + 1,
+        })
+        .collect()
+}
+
+pub fn merge(
+    self_hostname: &Name,
+    registries: &mut Vec<Registry>,
+    peers: Vec<Peer>,
+    max_hops: u32,
+    max_peers: Option<usize>,
+    https_only: bool,
+) -> Membership {
+    let mut membership = Membership::default();
+
+    let mut candidates: Vec<(Registry, u32)> = vec![];
+    for peer in peers {
+        let registry: Registry = match peer.url.parse() {
+            Ok(registry) => registry,
+            Err(err) => {
+                debug!("Ignoring peer `{}`.\nError: {}", peer.url, err);
+                continue;
+            }
+        };
+        if registry.hostname().to_string() != peer.hostname {
+            debug!(
+                "Ignoring peer `{}`, its URL `{}` does not match.",
+                peer.hostname, peer.url
+            );
+            continue;
+        }
+        if https_only && registry.url().scheme() != "https" {
+            debug!(
+                "Ignoring peer `{}`, its URL `{}` does not use `https`.",
+                peer.hostname, peer.url
+            );
+            continue;
+        }
+
+        // only registries from `REGISTRY_URLS` are 0 hops away
+        candidates.push((registry, peer.hops.max(1)));
+    }
+    candidates.sort_by_key(|(_, hops)| *hops);
+
+    let advertised: HashSet<Name> = candidates
+        .iter()
+        .map(|(registry, _)| registry.hostname().clone())
+        .collect();
+
+    registries.retain(|registry| {
+        let stale = registry.hops() > 0
+            && !registry.is_reachable()
+            && registry.last_error().is_some()
+            && !advertised.contains(registry.hostname());
+        if stale {
+            membership.removed.push(registry.clone());
+        }
+
+        !stale
+    });
+
+    for (mut registry, hops) in candidates {
+        if hops > max_hops {
+            continue;
+        }
+
+        registry.set_hops(hops);
+        if admit(self_hostname, registries, registry.clone(), max_peers) {
+            membership.added.push(registry);
+        }
+    }
+
+    membership
+}
+
+pub fn admit(
+    self_hostname: &Name,
+    registries: &mut Vec<Registry>,
+    registry: Registry,
+    max_peers: Option<usize>,
+) -> bool {
+    if registry.hostname() == self_hostname || is_forgotten(registry.hostname()) {
+        return false;
+    }
+
+    if let Some(known) = registries
+        .iter_mut()
+        .find(|known| known.hostname() == registry.hostname())
+    {
+        if registry.hops() < known.hops() {
+            known.set_hops(registry.hops());
+        }
+        return false;
+    }
+
+    if max_peers.is_some_and(|max_peers| registries.len() >= max_peers) {
+        warn!(
+            "Mesh is full with {} registries, ignoring registry `{}`.",
+            registries.len(),
+            registry.hostname()
+        );
+        return false;
+    }
+
+    registries.push(registry);
+
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(url: &str, hops: u32) -> Peer {
+        let registry: Registry = url.parse().unwrap();
+
+        Peer {
+            hostname: registry.hostname().to_string(),
+            url: url.to_string(),
+            hops,
+        }
+    }
+
+    #[test]
+    fn test_peers() {
+        let mut bob: Registry = "http://bob.com:3000".parse().unwrap();
+        bob.record_success();
+        let mut charlie: Registry = "http://charlie.com:3000".parse().unwrap();
+        charlie.set_hops(1);
+        charlie.record_success();
+        let dave: Registry = "http://dave.com:3000".parse().unwrap();
+
+        assert_eq!(
+            peers(&[bob, charlie, dave]),
+            vec![
+                peer("http://bob.com:3000/", 1),
+                peer("http://charlie.com:3000/", 2)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_merge() {
+        let self_hostname: Name = "alice.com".parse().unwrap();
+        let mut registries: Vec<Registry> = vec!["http://bob.com:3000".parse().unwrap()];
+
+        let membership = merge(
+            &self_hostname,
+            &mut registries,
+            vec![
+                peer("http://alice.com:3000", 1),
+                peer("http://bob.com:3000", 2),
+                peer("http://carol.com:3000", 2),
+                peer("http://carol.com:3000", 1),
+                peer("http://dave.com:3000", 3),
+                peer("http://erin.com:3000", 1),
+                peer("http://grace.com:3000", 2),
+                Peer {
+                    hostname: "frank.com".to_string(),
+                    url: "http://mallory.com:3000".to_string(),
+                    hops: 1,
+                },
+            ],
+            2,
+            Some(3),
+            false,
+        );
+
+        let hostnames = |registries: &[Registry]| -> Vec<String> {
+            registries
+                .iter()
+                .map(|registry| registry.hostname().to_string())
+                .collect()
+        };
+        assert_eq!(hostnames(&membership.added), vec!["carol.com", "erin.com"]);
+        assert!(membership.removed.is_empty());
+        assert_eq!(registries[0].hops(), 0);
+        assert_eq!(registries[1].hops(), 1);
+
+        registries.pop();
+        registries[1].record_error("Connection refused".to_string());
+        forget(&"erin.com".parse().unwrap());
+        let membership = merge(
+            &self_hostname,
+            &mut registries,
+            vec![peer("http://erin.com:3000", 1)],
+            2,
+            None,
+            false,
+        );
+        assert_eq!(hostnames(&membership.removed), vec!["carol.com"]);
+        assert!(membership.added.is_empty());
+        assert_eq!(hostnames(&registries), vec!["bob.com"]);
+    }
+
+    #[test]
+    fn test_merge_https_only() {
+        let self_hostname: Name = "alice.com".parse().unwrap();
+        let mut registries: Vec<Registry> = vec![];
+
+        let membership = merge(
+            &self_hostname,
+            &mut registries,
+            vec![
+                peer("http://bob.com:3000", 1),
+                peer("https://carol.com:3000", 0),
+            ],
+            2,
+            None,
+            true,
+        );
+        assert_eq!(membership.added.len(), 1);
+        assert_eq!(registries[0].hostname().to_string(), "carol.com");
+        assert_eq!(registries[0].hops(), 1);
+    }
+
+    #[test]
+    fn test_merge_introduced() {
+        let self_hostname: Name = "alice.com".parse().unwrap();
+        let mut registries: Vec<Registry> = vec![];
+
+        let mut grace: Registry = "http://grace.com:3000".parse().unwrap();
+        grace.set_hops(1);
+        assert!(admit(&self_hostname, &mut registries, grace, None));
+
+        // never contacted yet, kept until a request to it fails
+        let membership = merge(&self_hostname, &mut registries, vec![], 2, None, false);
+        assert!(membership.removed.is_empty());
+        assert_eq!(registries.len(), 1);
+
+        registries[0].record_success();
+        let membership = merge(&self_hostname, &mut registries, vec![], 2, None, false);
+        assert!(membership.removed.is_empty());
+
+        registries[0].record_error("Connection refused".to_string());
+        let membership = merge(&self_hostname, &mut registries, vec![], 2, None, false);
+        assert_eq!(membership.removed.len(), 1);
+        assert!(registries.is_empty());
+    }
+}
@@ -4,22 +4,32 @@ use env_logger::Builder;
 use hickory_server::ServerFuture;
 use log::{error, info, LevelFilter};
 use tokio::{
-    net::UdpSocket,
+    net::{TcpListener, UdpSocket},
     signal::unix::{signal, SignalKind},
     sync::Mutex,
 };
 
-use api::{collect_registry_services, dispatch_registry_services, start_api_server};
+use api::{
+    collect_registry_services, dispatch_registry_services, expire_registry_services,
+    gossip_registries, heartbeat_registries, max_sync_delay, start_api_server,
+    sync_registry_services,
+};
+use conflict::watch_conflicts;
 use dns::Dns;
 use docker::Docker;
 use env::Env;
 
 mod api;
+mod conflict;
 mod constants;
 mod dns;
 mod docker;
 mod env;
+mod gossip;
+mod metrics;
 mod registry;
+mod tls;
+mod watch;
 
 #[tokio::main]
 async fn main() {
@@ -61,11 +71,12 @@ async fn main() {
         let registries = registries.clone();
 
         tokio::spawn(async move {
-            let docker = Docker::new().unwrap_or_else(|err| {
-                panic!("{}", err);
+            let docker = Docker::new().await.unwrap_or_else(|err| {
+                panic!("{}\nError: {}", err, err.root_cause());
             });
 
             docker.flush_registry_services(self_registry.clone()).await;
+            gossip_registries(self_registry.clone(), registries.clone()).await;
             collect_registry_services(registries.clone()).await;
             dispatch_registry_services(self_registry.clone(), registries.clone()).await;
 
@@ -78,13 +89,60 @@ async fn main() {
         })
     };
 
+    if let Some(interval) = Env::registry_sync_interval() {
+        let self_registry = self_registry.clone();
+        let registries = registries.clone();
+
+        tokio::spawn(async move {
+            sync_registry_services(self_registry.clone(), registries.clone(), interval).await;
+        });
+    }
+
+    if let Some(ttl) = Env::registry_ttl() {
+        let registries = registries.clone();
+
+        tokio::spawn(async move {
+            expire_registry_services(registries.clone(), ttl).await;
+        });
+    }
+
+    if let Some(ttl) = Env::registry_ttl().filter(|ttl| {
+        Env::registry_sync_interval().is_none_or(|interval| max_sync_delay(interval) > *ttl / 3)
+    }) {
+        let self_registry = self_registry.clone();
+        let registries = registries.clone();
+
+        tokio::spawn(async move {
+            heartbeat_registries(self_registry.clone(), registries.clone(), ttl).await;
+        });
+    }
+
+    {
+        let self_registry = self_registry.clone();
+        let registries = registries.clone();
+
+        tokio::spawn(async move {
+            watch_conflicts(
+                self_registry,
+                registries,
+                Env::service_conflict_policy(),
+                Env::service_priority(),
+            )
+            .await;
+        });
+    }
+
     let dns_job = {
         let self_registry = self_registry.clone();
         let registries = registries.clone();
 
         tokio::spawn(async move {
-            let mut dns_server =
-                ServerFuture::new(Dns::new(self_registry.clone(), registries.clone()));
+            let mut dns_server = ServerFuture::new(Dns::new(
+                self_registry.clone(),
+                registries.clone(),
+                Env::service_conflict_policy(),
+                Env::service_priority(),
+            ));
 
             let addr = Env::server_listen();
             let socket = UdpSocket::bind(addr).await.unwrap_or_else(|err| {
@@ -92,7 +150,15 @@ async fn main() {
             });
             dns_server.register_socket(socket);
 
-            info!("DNS server listening on: {}", addr);
+            let listener = TcpListener::bind(addr).await.unwrap_or_else(|err| {
+                panic!(
+                    "DNS server failed to listen on `{}` over TCP.\nError: {}",
+                    addr, err
+                );
+            });
+            dns_server.register_listener(listener, Env::server_tcp_timeout());
+
+            info!("DNS server listening on: {} (UDP and TCP)", addr);
             let _ = dns_server.block_until_done().await;
         })
     };
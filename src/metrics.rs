@@ -0,0 +1,129 @@
+use std::sync::LazyLock;
+
+use anyhow::{Context, Result};
+use prometheus::{
+    register_histogram, register_int_counter, register_int_counter_vec, register_int_gauge_vec,
+    Encoder, Histogram, IntCounter, IntCounterVec, IntGaugeVec, TextEncoder,
+};
+
+use crate::registry::Registry;
+
+pub static DNS_QUERIES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!("dns_queries_total", "DNS queries by outcome.", &["outcome"]).unwrap()
+});
+
+pub static DNS_REQUEST_DURATION: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!(
+        "dns_request_duration_seconds",
+        "Time spent handling a DNS request.",
+        vec![0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5]
+    )
+    .unwrap()
+});
+
+pub static DOCKER_EVENTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "docker_events_total",
+        "Docker container events by action.",
+        &["action"]
+    )
+    .unwrap()
+});
+
+pub static DOCKER_RECONNECTS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "docker_reconnects_total",
+        "Reconnects to the Docker event stream."
+    )
+    .unwrap()
+});
+
+pub static REGISTRY_REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "registry_requests_total",
+        "Collect and dispatch requests to other registries by result.",
+        &["registry", "operation", "result"]
+    )
+    .unwrap()
+});
+
+pub static REGISTRY_SERVICES: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "registry_services",
+        "Services known per registry.",
+        &["registry", "visibility"]
+    )
+    .unwrap()
+});
+
+pub fn docker_event_action(action: &str) -> &str {
+    action.split(':').next().unwrap_or(action).trim()
+}
+
+pub fn record_registry_services(self_registry: &Registry, registries: &[Registry]) {
+    REGISTRY_SERVICES.reset();
+
+    for registry in [self_registry].into_iter().chain(registries) {
+        let hostname = registry.hostname().to_string();
+        REGISTRY_SERVICES
+            .with_label_values(&[&hostname, "public"])
+            .set(registry.public_services().len() as i64);
+        REGISTRY_SERVICES
+            .with_label_values(&[&hostname, "private"])
+            .set(registry.private_services().len() as i64);
+    }
+}
+
+pub fn encode() -> Result<String> {
+    let mut buffer = vec![];
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buffer)
+        .context("Failed to encode metrics.")?;
+
+    String::from_utf8(buffer).context("Failed to encode metrics.")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_docker_event_action() {
+        let test_cases = vec![
+            ("start", "start"),
+            ("exec_start: /bin/sh -c true", "exec_start"),
+            ("health_status: healthy", "health_status"),
+        ];
+
+        for (input, expected) in test_cases {
+            assert_eq!(
+                docker_event_action(input),
+                expected,
+                "Failed for: {}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_record_registry_services() {
+        let mut self_registry: Registry = "http://alice.com:3000".parse().unwrap();
+        self_registry.add_public_service("app".to_string(), None);
+        self_registry.add_private_service("db".to_string(), None);
+        let registry: Registry = "http://bob.com:3000".parse().unwrap();
+
+        record_registry_services(&self_registry, &[registry]);
+        let metrics = encode().unwrap();
+        assert!(
+            metrics.contains(r#"registry_services{registry="alice.com",visibility="public"} 1"#)
+        );
+        assert!(
+            metrics.contains(r#"registry_services{registry="alice.com",visibility="private"} 1"#)
+        );
+        assert!(metrics.contains(r#"registry_services{registry="bob.com",visibility="public"} 0"#));
+
+        record_registry_services(&self_registry, &[]);
+        let metrics = encode().unwrap();
+        assert!(!metrics.contains(r#"registry="bob.com""#));
+    }
+}
@@ -1,7 +1,16 @@
-use std::{collections::HashSet, str::FromStr};
+use std::{
+    collections::{BTreeSet, HashMap, HashSet, VecDeque},
+    fmt,
+    net::IpAddr,
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use anyhow::{anyhow, Context, Error, Result};
-use hickory_server::proto::rr::{Name, RData};
+use hickory_server::proto::rr::{
+    rdata::{A, AAAA, SRV},
+    Name, RData, RecordData, RecordType,
+};
 use reqwest::Url;
 use serde::{
     de::{self},
@@ -10,6 +19,119 @@ use serde::{
 
 use crate::dns::Dns;
 
+pub type Services = HashMap<String, BTreeSet<u16>>;
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ServicesPayload {
+    Ports(Services),
+    Names(HashSet<String>),
+}
+
+const HISTORY_CAPACITY: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Update {
+    Snapshot {
+        #[serde(default)]
+        epoch: u64,
+        generation: u64,
+        services: Services,
+    },
+    Delta {
+        #[serde(default)]
+        epoch: u64,
+        base: u64,
+        generation: u64,
+        added: Services,
+        removed: BTreeSet<String>,
+    },
+}
+
+impl Update {
+    pub fn epoch(&self) -> u64 {
+        match self {
+            Update::Snapshot { epoch, .. } | Update::Delta { epoch, .. } => *epoch,
+        }
+    }
+
+    pub fn generation(&self) -> u64 {
+        match self {
+            Update::Snapshot { generation, .. } | Update::Delta { generation, .. } => *generation,
+        }
+    }
+
+    pub fn is_delta(&self) -> bool {
+        matches!(self, Update::Delta { .. })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UpdateError {
+    Stale {
+        epoch: u64,
+        generation: u64,
+        current_epoch: u64,
+        current: u64,
+    },
+    OutOfSync { base: u64, current: u64 },
+}
+
+impl fmt::Display for UpdateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UpdateError::Stale {
+                epoch,
+                generation,
+                current_epoch,
+                current,
+            } => write!(
+                f,
+                "Update generation `{}` of epoch `{}` is older than current generation `{}` of epoch `{}`.",
+                generation, epoch, current, current_epoch
+            ),
+            UpdateError::OutOfSync { base, current } => write!(
+                f,
+                "Update base generation `{}` does not match current generation `{}`.",
+                base, current
+            ),
+        }
+    }
+}
+
+impl std::error::Error for UpdateError {}
+
+#[derive(Debug, Clone)]
+struct Delta {
+    base: u64,
+    added: Services,
+    removed: BTreeSet<String>,
+}
+
+pub fn parse_update(s: &str) -> serde_json::Result<Update> {
+    match serde_json::from_str(s) {
+        Ok(update) => Ok(update),
+        Err(_) => parse_services(s).map(|services| Update::Snapshot {
+            epoch: 0,
+            generation: 0,
+            services,
+        }),
+    }
+}
+
+pub fn parse_services(s: &str) -> serde_json::Result<Services> {
+    let services = match serde_json::from_str(s)? {
+        ServicesPayload::Ports(services) => services,
+        ServicesPayload::Names(names) => names
+            .into_iter()
+            .map(|name| (name, Default::default()))
+            .collect(),
+    };
+
+    Ok(services)
+}
+
 fn serialize_hostname<S>(hostname: &Name, serializer: S) -> Result<S::Ok, S::Error>
 where
     S: Serializer,
@@ -40,6 +162,23 @@ where
     Url::parse(&url).map_err(de::Error::custom)
 }
 
+fn serialize_time<S>(time: &Option<SystemTime>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    time.and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|duration| duration.as_secs())
+        .serialize(serializer)
+}
+
+fn deserialize_time<'de, D>(deserializer: D) -> Result<Option<SystemTime>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let secs = Option::<u64>::deserialize(deserializer)?;
+    Ok(secs.map(|secs| UNIX_EPOCH + Duration::from_secs(secs)))
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Registry {
     #[serde(
@@ -51,9 +190,64 @@ pub struct Registry {
     #[serde(serialize_with = "serialize_url", deserialize_with = "deserialize_url")]
     url: Url,
 
-    public_services: HashSet<String>,
+    public_services: Services,
+
+    private_services: Services,
+
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    service_addresses: HashMap<String, HashMap<String, BTreeSet<IpAddr>>>,
+
+    #[serde(
+        default,
+        serialize_with = "serialize_time",
+        deserialize_with = "deserialize_time"
+    )]
+    last_success: Option<SystemTime>,
+
+    #[serde(
+        default,
+        serialize_with = "serialize_time",
+        deserialize_with = "deserialize_time"
+    )]
+    last_error_at: Option<SystemTime>,
+
+    #[serde(default)]
+    last_error: Option<String>,
+
+    #[serde(
+        default,
+        serialize_with = "serialize_time",
+        deserialize_with = "deserialize_time"
+    )]
+    last_refreshed: Option<SystemTime>,
+
+    #[serde(default = "default_healthy")]
+    healthy: bool,
 
-    private_services: HashSet<String>,
+    #[serde(default)]
+    epoch: u64,
+
+    #[serde(default)]
+    generation: u64,
+
+    #[serde(default)]
+    dispatched_generation: Option<u64>,
+
+    #[serde(default)]
+    hops: u32,
+
+    #[serde(skip)]
+    history: VecDeque<Delta>,
+
+    #[serde(skip)]
+    collected_etag: Option<String>,
+
+    #[serde(skip)]
+    legacy: bool,
+}
+
+fn default_healthy() -> bool {
+    true
 }
 
 impl Registry {
@@ -63,6 +257,19 @@ impl Registry {
             url,
             public_services: Default::default(),
             private_services: Default::default(),
+            service_addresses: Default::default(),
+            last_success: None,
+            last_error_at: None,
+            last_error: None,
+            last_refreshed: None,
+            healthy: true,
+            epoch: 0,
+            generation: 0,
+            dispatched_generation: None,
+            hops: 0,
+            history: Default::default(),
+            collected_etag: None,
+            legacy: false,
         }
     }
 
@@ -74,28 +281,42 @@ impl Registry {
         &self.url
     }
 
-    pub fn public_services(&self) -> &HashSet<String> {
+    pub fn public_services(&self) -> &Services {
         &self.public_services
     }
 
-    pub fn private_services(&self) -> &HashSet<String> {
+    pub fn private_services(&self) -> &Services {
         &self.private_services
     }
 
     pub fn has_public_service(&self, service: &str) -> bool {
-        self.public_services.contains(service)
+        self.public_services.contains_key(service)
     }
 
     pub fn has_private_service(&self, service: &str) -> bool {
-        self.private_services.contains(service)
+        self.private_services.contains_key(service)
+    }
+
+    pub fn service_ports(&self, service: &str, public_only: bool) -> BTreeSet<u16> {
+        let public_ports = self.public_services.get(service).into_iter().flatten();
+        let private_ports = self
+            .private_services
+            .get(service)
+            .filter(|_| !public_only)
+            .into_iter()
+            .flatten();
+
+        public_ports.chain(private_ports).copied().collect()
     }
 
-    pub fn add_public_service(&mut self, service: String) {
-        self.public_services.insert(service);
+    pub fn add_public_service(&mut self, service: String, port: Option<u16>) {
+        let ports = self.public_services.entry(service).or_default();
+        ports.extend(port);
     }
 
-    pub fn add_private_service(&mut self, service: String) {
-        self.private_services.insert(service);
+    pub fn add_private_service(&mut self, service: String, port: Option<u16>) {
+        let ports = self.private_services.entry(service).or_default();
+        ports.extend(port);
     }
 
     pub fn clear_public_services(&mut self) {
@@ -106,8 +327,306 @@ impl Registry {
         self.private_services.clear();
     }
 
-    pub fn flush_public_services(&mut self, services: HashSet<String>) {
-        self.public_services = services;
+    pub fn service_addresses(&self, service: &str, network: &str) -> BTreeSet<IpAddr> {
+        self.service_addresses
+            .get(service)
+            .and_then(|networks| networks.get(network))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub fn add_service_address(&mut self, service: String, network: String, address: IpAddr) {
+        let networks = self.service_addresses.entry(service).or_default();
+        networks.entry(network).or_default().insert(address);
+    }
+
+    pub fn clear_service_addresses(&mut self) {
+        self.service_addresses.clear();
+    }
+
+    pub fn record_success(&mut self) {
+        self.last_success = Some(SystemTime::now());
+    }
+
+    pub fn record_error(&mut self, error: String) {
+        self.last_error_at = Some(SystemTime::now());
+        self.last_error = Some(error);
+    }
+
+    pub fn last_error(&self) -> Option<&str> {
+        self.last_error.as_deref()
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.healthy
+    }
+
+    pub fn is_reachable(&self) -> bool {
+        match (self.last_success, self.last_error_at) {
+            (Some(last_success), Some(last_error_at)) => {
+                self.healthy && last_success >= last_error_at
+            }
+            (Some(_), None) => self.healthy,
+            (None, _) => false,
+        }
+    }
+
+    pub fn hops(&self) -> u32 {
+        self.hops
+    }
+
+    pub fn set_hops(&mut self, hops: u32) {
+        self.hops = hops;
+    }
+
+    pub fn expire(&mut self, ttl: Duration) -> bool {
+        let expired = self
+            .last_refreshed
+            .and_then(|last_refreshed| last_refreshed.elapsed().ok())
+            .is_some_and(|elapsed| elapsed > ttl);
+        if self.healthy && expired {
+            self.healthy = false;
+
+            return true;
+        }
+
+        false
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    pub fn start_epoch(&mut self) {
+        // boot time orders epochs across restarts, so peers can tell an old epoch from a new one
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_nanos() as u64);
+        self.epoch = now.max(self.epoch + 1);
+        self.generation = 0;
+        self.history.clear();
+    }
+
+    pub fn reset_generation(&mut self) {
+        self.epoch = 0;
+        self.generation = 0;
+        self.collected_etag = None;
+    }
+
+    pub fn dispatched_generation(&self) -> Option<u64> {
+        self.dispatched_generation
+    }
+
+    pub fn set_dispatched_generation(&mut self, generation: Option<u64>) {
+        self.dispatched_generation = generation;
+    }
+
+    pub fn commit_public_services(&mut self, previous: &Services) {
+        let added: Services = self
+            .public_services
+            .iter()
+            .filter(|(service, ports)| previous.get(*service) != Some(ports))
+            .map(|(service, ports)| (service.clone(), ports.clone()))
+            .collect();
+        let removed: BTreeSet<String> = previous
+            .keys()
+            .filter(|service| !self.public_services.contains_key(*service))
+            .cloned()
+            .collect();
+        if added.is_empty() && removed.is_empty() {
+            return;
+        }
+
+        let base = self.generation;
+        self.generation = base + 1;
+        self.history.push_back(Delta {
+            base,
+            added,
+            removed,
+        });
+        if self.history.len() > HISTORY_CAPACITY {
+            self.history.pop_front();
+        }
+    }
+
+    pub fn snapshot(&self) -> Update {
+        Update::Snapshot {
+            epoch: self.epoch,
+            generation: self.generation,
+            services: self.public_services.clone(),
+        }
+    }
+
+    pub fn update_since(&self, epoch: u64, generation: u64) -> Update {
+        let start = if epoch != self.epoch {
+            return self.snapshot();
+        } else if generation == self.generation {
+            self.history.len()
+        } else if let Some(start) = self
+            .history
+            .iter()
+            .position(|delta| delta.base == generation)
+        {
+            start
+        } else {
+            return self.snapshot();
+        };
+
+        let mut added = Services::new();
+        let mut removed = BTreeSet::new();
+        for delta in self.history.iter().skip(start) {
+            for service in &delta.removed {
+                added.remove(service);
+                removed.insert(service.clone());
+            }
+            for (service, ports) in &delta.added {
+                removed.remove(service);
+                added.insert(service.clone(), ports.clone());
+            }
+        }
+
+        Update::Delta {
+            epoch: self.epoch,
+            base: generation,
+            generation: self.generation,
+            added,
+            removed,
+        }
+    }
+
+    pub fn apply_update(&mut self, update: Update) -> Result<(), UpdateError> {
+        let current = self.generation;
+        let stale = UpdateError::Stale {
+            epoch: update.epoch(),
+            generation: update.generation(),
+            current_epoch: self.epoch,
+            current,
+        };
+        // epoch 0 is an unversioned list of names from a registry that hasn't been upgraded
+        if update.epoch() != 0 && update.epoch() < self.epoch {
+            return Err(stale);
+        } else if update.epoch() != self.epoch {
+            if let Update::Delta { base, .. } = update {
+                return Err(UpdateError::OutOfSync { base, current });
+            }
+        } else if update.generation() < current {
+            return Err(stale);
+        }
+
+        match update {
+            Update::Snapshot {
+                epoch,
+                generation,
+                services,
+            } => {
+                self.public_services = services;
+                self.epoch = epoch;
+                self.generation = generation;
+            }
+            Update::Delta {
+                base,
+                generation,
+                added,
+                removed,
+                ..
+            } => {
+                if base != current {
+                    return Err(if generation == current {
+                        stale
+                    } else {
+                        UpdateError::OutOfSync { base, current }
+                    });
+                }
+
+                for service in removed {
+                    self.public_services.remove(&service);
+                }
+                self.public_services.extend(added);
+                self.generation = generation;
+            }
+        }
+        self.refresh();
+
+        Ok(())
+    }
+
+    pub fn refresh(&mut self) {
+        self.last_refreshed = Some(SystemTime::now());
+        self.healthy = true;
+    }
+
+    pub fn collected_etag(&self) -> Option<&str> {
+        self.collected_etag.as_deref()
+    }
+
+    pub fn set_collected_etag(&mut self, etag: Option<String>) {
+        self.collected_etag = etag;
+    }
+
+    pub fn is_legacy(&self) -> bool {
+        self.legacy
+    }
+
+    pub fn set_legacy(&mut self, legacy: bool) {
+        self.legacy = legacy;
+    }
+
+    pub fn try_into_rdata(&self, record_type: RecordType) -> Result<RData> {
+        let data = Dns::query_upstream(&self.hostname.to_string(), record_type);
+        if let Some(data) = data {
+            Ok(data)
+        } else {
+            Err(anyhow!(
+                "No {} record found for hostname `{}`.",
+                record_type,
+                self.hostname
+            ))
+        }
+    }
+
+    pub fn try_into_service_rdata(
+        &self,
+        service: &str,
+        network: Option<&str>,
+        record_type: RecordType,
+    ) -> Result<RData> {
+        let addresses = network
+            .map(|network| self.service_addresses(service, network))
+            .unwrap_or_default();
+        if addresses.is_empty() {
+            return self.try_into_rdata(record_type);
+        }
+
+        let address = addresses.into_iter().find(|address| match record_type {
+            RecordType::A => address.is_ipv4(),
+            RecordType::AAAA => address.is_ipv6(),
+            _ => false,
+        });
+
+        match address {
+            Some(IpAddr::V4(ip)) => Ok(A(ip).into_rdata()),
+            Some(IpAddr::V6(ip)) => Ok(AAAA(ip).into_rdata()),
+            None => Err(anyhow!(
+                "No {} record found for service `{}` on network `{}`.",
+                record_type,
+                service,
+                network.unwrap_or_default()
+            )),
+        }
+    }
+
+    pub fn srv_rdata(&self, service: &str, public_only: bool) -> Vec<RData> {
+        let mut target = self.hostname.clone();
+        target.set_fqdn(true);
+
+        self.service_ports(service, public_only)
+            .into_iter()
+            .map(|port| SRV::new(0, 0, port, target.clone()).into_rdata())
+            .collect()
     }
 }
 
@@ -131,15 +650,9 @@ impl TryInto<RData> for Registry {
     type Error = Error;
 
     fn try_into(self) -> Result<RData> {
-        let data = Dns::query_upstream(&self.hostname.to_string());
-        if let Some(data) = data {
-            Ok(data)
-        } else {
-            Err(anyhow!(
-                "No IPv4 address found for hostname `{}`.",
-                self.hostname
-            ))
-        }
+        self.try_into_rdata(RecordType::A)
+            .or_else(|_| self.try_into_rdata(RecordType::AAAA))
+            .map_err(|_| anyhow!("No IP address found for hostname `{}`.", self.hostname))
     }
 }
 
@@ -147,9 +660,7 @@ impl TryInto<RData> for Registry {
 mod tests {
     use super::*;
 
-    use std::net::Ipv4Addr;
-
-    use hickory_server::proto::rr::{rdata::A, RecordData};
+    use std::net::{Ipv4Addr, Ipv6Addr};
 
     #[test]
     fn test_registry_from_str() {
@@ -167,4 +678,284 @@ mod tests {
         let data: RData = registry.try_into().unwrap();
         assert_eq!(data, A(Ipv4Addr::new(127, 0, 0, 1)).into_rdata());
     }
+
+    #[test]
+    fn test_registry_try_into_rdata() {
+        let registry = Registry::from_str("http://localhost:8080").unwrap();
+        let data = registry.try_into_rdata(RecordType::A).unwrap();
+        assert_eq!(data, A(Ipv4Addr::new(127, 0, 0, 1)).into_rdata());
+
+        let registry = Registry::from_str("http://unknown.invalid:8080").unwrap();
+        assert!(registry.try_into_rdata(RecordType::A).is_err());
+        assert!(registry.try_into_rdata(RecordType::AAAA).is_err());
+    }
+
+    #[test]
+    fn test_parse_services() {
+        let services = parse_services(r#"["foo", "bar"]"#).unwrap();
+        assert_eq!(
+            services,
+            Services::from([
+                ("foo".to_string(), BTreeSet::new()),
+                ("bar".to_string(), BTreeSet::new()),
+            ])
+        );
+
+        let services = parse_services(r#"{"foo": [443, 8443], "bar": []}"#).unwrap();
+        assert_eq!(
+            services,
+            Services::from([
+                ("foo".to_string(), BTreeSet::from([443, 8443])),
+                ("bar".to_string(), BTreeSet::new()),
+            ])
+        );
+
+        assert!(parse_services("null").is_err());
+        assert!(parse_services(r#"{"foo": ["bar"]}"#).is_err());
+    }
+
+    #[test]
+    fn test_registry_srv_rdata() {
+        let mut registry = Registry::from_str("http://alice.com:3000").unwrap();
+        registry.add_public_service("foo".to_string(), Some(8443));
+        registry.add_private_service("foo".to_string(), Some(443));
+        registry.add_public_service("bar".to_string(), None);
+
+        let target = Name::from_str("alice.com.").unwrap();
+        assert_eq!(
+            registry.srv_rdata("foo", false),
+            vec![
+                SRV::new(0, 0, 443, target.clone()).into_rdata(),
+                SRV::new(0, 0, 8443, target.clone()).into_rdata(),
+            ]
+        );
+        assert_eq!(
+            registry.srv_rdata("foo", true),
+            vec![SRV::new(0, 0, 8443, target.clone()).into_rdata()]
+        );
+        assert!(registry.srv_rdata("bar", false).is_empty());
+        assert!(registry.srv_rdata("unknown", false).is_empty());
+    }
+
+    #[test]
+    fn test_registry_try_into_service_rdata() {
+        let mut registry = Registry::from_str("http://localhost:8080").unwrap();
+        registry.add_public_service("foo".to_string(), None);
+        registry.add_service_address(
+            "foo".to_string(),
+            "caddy".to_string(),
+            IpAddr::V4(Ipv4Addr::new(172, 18, 0, 2)),
+        );
+        registry.add_service_address(
+            "foo".to_string(),
+            "caddy".to_string(),
+            IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2)),
+        );
+
+        let data = registry
+            .try_into_service_rdata("foo", Some("caddy"), RecordType::A)
+            .unwrap();
+        assert_eq!(data, A(Ipv4Addr::new(172, 18, 0, 2)).into_rdata());
+
+        let data = registry
+            .try_into_service_rdata("foo", Some("caddy"), RecordType::AAAA)
+            .unwrap();
+        assert_eq!(
+            data,
+            AAAA(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2)).into_rdata()
+        );
+
+        let data = registry
+            .try_into_service_rdata("foo", Some("other"), RecordType::A)
+            .unwrap();
+        assert_eq!(data, A(Ipv4Addr::new(127, 0, 0, 1)).into_rdata());
+
+        let data = registry
+            .try_into_service_rdata("foo", None, RecordType::A)
+            .unwrap();
+        assert_eq!(data, A(Ipv4Addr::new(127, 0, 0, 1)).into_rdata());
+
+        registry.clear_service_addresses();
+        registry.add_service_address(
+            "foo".to_string(),
+            "caddy".to_string(),
+            IpAddr::V4(Ipv4Addr::new(172, 18, 0, 2)),
+        );
+        assert!(registry
+            .try_into_service_rdata("foo", Some("caddy"), RecordType::AAAA)
+            .is_err());
+    }
+
+    #[test]
+    fn test_registry_sync_status_serde() {
+        let mut registry = Registry::from_str("http://localhost:8080").unwrap();
+        registry.record_success();
+        registry.record_error("Connection refused".to_string());
+
+        let json = serde_json::to_value(&registry).unwrap();
+        assert!(json["last_success"].is_u64());
+        assert!(json["last_error_at"].is_u64());
+        assert_eq!(json["last_error"], "Connection refused");
+
+        let registry: Registry = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(serde_json::to_value(&registry).unwrap(), json);
+    }
+
+    #[test]
+    fn test_registry_expire() {
+        let ttl = Duration::from_secs(60);
+        let mut registry = Registry::from_str("http://localhost:8080").unwrap();
+
+        // never refreshed, nothing to expire
+        assert!(!registry.expire(ttl));
+        assert!(registry.is_healthy());
+
+        let update = Update::Snapshot {
+            epoch: 0,
+            generation: 0,
+            services: Services::from([("foo".to_string(), BTreeSet::new())]),
+        };
+        registry.apply_update(update.clone()).unwrap();
+        assert!(!registry.expire(ttl));
+        assert!(registry.is_healthy());
+
+        registry.last_refreshed = Some(SystemTime::now() - Duration::from_secs(120));
+        assert!(registry.expire(ttl));
+        assert!(!registry.is_healthy());
+        assert!(!registry.expire(ttl));
+        assert!(registry.has_public_service("foo"));
+
+        registry.apply_update(update).unwrap();
+        assert!(registry.is_healthy());
+    }
+
+    #[test]
+    fn test_registry_update_since() {
+        let mut registry = Registry::from_str("http://localhost:8080").unwrap();
+        registry.start_epoch();
+        let epoch = registry.epoch();
+        let start = registry.generation();
+
+        let previous = registry.public_services().clone();
+        registry.add_public_service("foo".to_string(), None);
+        registry.add_public_service("bar".to_string(), Some(8080));
+        registry.commit_public_services(&previous);
+        let first = registry.generation();
+        assert!(first > start);
+
+        let previous = registry.public_services().clone();
+        registry.clear_public_services();
+        registry.add_public_service("bar".to_string(), Some(8443));
+        registry.add_public_service("baz".to_string(), None);
+        registry.commit_public_services(&previous);
+        let second = registry.generation();
+        assert!(second > first);
+
+        let previous = registry.public_services().clone();
+        registry.commit_public_services(&previous);
+        assert_eq!(registry.generation(), second);
+
+        assert_eq!(
+            registry.update_since(epoch, start),
+            Update::Delta {
+                epoch,
+                base: start,
+                generation: second,
+                added: Services::from([
+                    ("bar".to_string(), BTreeSet::from([8443])),
+                    ("baz".to_string(), BTreeSet::new()),
+                ]),
+                removed: BTreeSet::from(["foo".to_string()]),
+            }
+        );
+        assert_eq!(
+            registry.update_since(epoch, first),
+            Update::Delta {
+                epoch,
+                base: first,
+                generation: second,
+                added: Services::from([
+                    ("bar".to_string(), BTreeSet::from([8443])),
+                    ("baz".to_string(), BTreeSet::new()),
+                ]),
+                removed: BTreeSet::from(["foo".to_string()]),
+            }
+        );
+        assert_eq!(
+            registry.update_since(epoch, second),
+            Update::Delta {
+                epoch,
+                base: second,
+                generation: second,
+                added: Services::new(),
+                removed: BTreeSet::new(),
+            }
+        );
+        assert_eq!(registry.update_since(0, start), registry.snapshot());
+    }
+
+    #[test]
+    fn test_registry_apply_update() {
+        let mut source = Registry::from_str("http://localhost:8080").unwrap();
+        source.start_epoch();
+        let mut registry = Registry::from_str("http://localhost:8080").unwrap();
+
+        registry.apply_update(source.snapshot()).unwrap();
+        assert_eq!(registry.generation(), source.generation());
+
+        let base = source.generation();
+        let previous = source.public_services().clone();
+        source.add_public_service("foo".to_string(), None);
+        source.commit_public_services(&previous);
+        let delta = source.update_since(source.epoch(), base);
+        registry.apply_update(delta.clone()).unwrap();
+        assert!(registry.has_public_service("foo"));
+        assert_eq!(registry.generation(), source.generation());
+
+        assert!(matches!(
+            registry.apply_update(delta.clone()),
+            Err(UpdateError::Stale { .. })
+        ));
+        assert!(matches!(
+            registry.apply_update(Update::Snapshot {
+                epoch: source.epoch(),
+                generation: base,
+                services: Services::new(),
+            }),
+            Err(UpdateError::Stale { .. })
+        ));
+
+        let previous = source.public_services().clone();
+        source.add_public_service("bar".to_string(), None);
+        source.commit_public_services(&previous);
+        registry.reset_generation();
+        assert!(matches!(
+            registry.apply_update(source.update_since(source.epoch(), delta.generation())),
+            Err(UpdateError::OutOfSync { .. })
+        ));
+
+        registry.apply_update(source.snapshot()).unwrap();
+        assert!(registry.has_public_service("bar"));
+        assert_eq!(registry.generation(), source.generation());
+
+        // a restarted source starts a new epoch with lower generations
+        source.start_epoch();
+        assert!(matches!(
+            registry.apply_update(source.update_since(source.epoch(), 0)),
+            Err(UpdateError::OutOfSync { .. })
+        ));
+        let previous_snapshot = registry.snapshot();
+        registry.apply_update(source.snapshot()).unwrap();
+        assert_eq!(registry.epoch(), source.epoch());
+        assert_eq!(registry.generation(), 0);
+
+        // a snapshot from the previous epoch arriving late is stale
+        assert!(previous_snapshot.epoch() < source.epoch());
+        assert!(matches!(
+            registry.apply_update(previous_snapshot),
+            Err(UpdateError::Stale { .. })
+        ));
+        assert_eq!(registry.epoch(), source.epoch());
+        assert_eq!(registry.generation(), 0);
+    }
 }
@@ -0,0 +1,163 @@
+use std::{any::Any, sync::Arc};
+
+use actix_tls::accept::rustls_0_23::TlsStream;
+use actix_web::{dev::Extensions, rt::net::TcpStream};
+use anyhow::{anyhow, Context, Result};
+use hickory_server::proto::rr::Name;
+use reqwest::{ClientBuilder, Identity};
+use rustls::{
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName},
+    server::WebPkiClientVerifier,
+    RootCertStore, ServerConfig,
+};
+use rustls_pemfile::{certs, private_key};
+use webpki::EndEntityCert;
+
+use crate::env::Env;
+
+#[derive(Debug, Clone)]
+pub struct PeerCertificate(CertificateDer<'static>);
+
+impl PeerCertificate {
+    pub fn is_valid_for(&self, hostname: &Name) -> bool {
+        let hostname = hostname.to_string();
+        let Ok(name) = ServerName::try_from(hostname.trim_end_matches('.')) else {
+            return false;
+        };
+
+        EndEntityCert::try_from(&self.0)
+            .and_then(|cert| cert.verify_is_valid_for_subject_name(&name))
+            .is_ok()
+    }
+}
+
+fn parse_certs(pem: &str) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = certs(&mut pem.as_bytes())
+        .collect::<Result<Vec<_>, _>>()
+        .context("Failed to parse PEM certificates.")?;
+    if certs.is_empty() {
+        return Err(anyhow!("No PEM certificate found."));
+    }
+
+    Ok(certs)
+}
+
+fn parse_private_key(pem: &str) -> Result<PrivateKeyDer<'static>> {
+    private_key(&mut pem.as_bytes())
+        .context("Failed to parse PEM private key.")?
+        .ok_or_else(|| anyhow!("No PEM private key found."))
+}
+
+fn parse_roots(pem: &str) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in parse_certs(pem)? {
+        roots
+            .add(cert)
+            .context("Failed to add CA certificate to the trust store.")?;
+    }
+
+    Ok(roots)
+}
+
+pub fn server_config() -> Result<Option<ServerConfig>> {
+    let (Some(cert), Some(key)) = (Env::registry_tls_cert(), Env::registry_tls_key()) else {
+        return Ok(None);
+    };
+
+    let certs = parse_certs(&cert).context("Invalid registry TLS certificate.")?;
+    let key = parse_private_key(&key).context("Invalid registry TLS private key.")?;
+
+    let builder = match Env::registry_tls_ca() {
+        Some(ca) => {
+            let roots = parse_roots(&ca).context("Invalid registry TLS CA bundle.")?;
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+                .build()
+                .context("Failed to build client certificate verifier.")?;
+
+            ServerConfig::builder().with_client_cert_verifier(verifier)
+        }
+        None => ServerConfig::builder().with_no_client_auth(),
+    };
+
+    let config = builder
+        .with_single_cert(certs, key)
+        .context("Failed to configure registry TLS certificate.")?;
+
+    Ok(Some(config))
+}
+
+pub fn configure_client(builder: ClientBuilder) -> Result<ClientBuilder> {
+    let mut builder = builder;
+
+    if let Some(ca) = Env::registry_tls_ca() {
+        for cert in reqwest::Certificate::from_pem_bundle(ca.as_bytes())
+            .context("Invalid registry TLS CA bundle.")?
+        {
+            builder = builder.add_root_certificate(cert);
+        }
+    }
+
+    if let (Some(cert), Some(key)) = (Env::registry_tls_cert(), Env::registry_tls_key()) {
+        let identity = Identity::from_pem(format!("{}\n{}", key, cert).as_bytes())
+            .context("Invalid registry TLS client identity.")?;
+        builder = builder.identity(identity);
+    }
+
+    Ok(builder)
+}
+
+pub fn on_connect(connection: &dyn Any, extensions: &mut Extensions) {
+    let Some(stream) = connection.downcast_ref::<TlsStream<TcpStream>>() else {
+        return;
+    };
+
+    let (_, session) = stream.get_ref();
+    if let Some(cert) = session.peer_certificates().and_then(|certs| certs.first()) {
+        extensions.insert(PeerCertificate(cert.clone().into_owned()));
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    use std::str::FromStr;
+
+    pub const TEST_CERT: &str = "-----BEGIN CERTIFICATE-----
+MIIBlDCCATugAwIBAgIUScY1PVotFf72NRYiqCXTdugTArQwCgYIKoZIzj0EAwIw
+FDESMBAGA1UEAwwJYWxpY2UuY29tMCAXDTI2MTAxNjIwMjkyNVoYDzIxMjYwOTIy
+MjAyOTI1WjAUMRIwEAYDVQQDDAlhbGljZS5jb20wWTATBgcqhkjOPQIBBggqhkjO
+PQMBBwNCAAS+Glx6IhjxXr64MWIoMsQoBFbwDQaje4dzL/hcM5mUOopuaB7NcYcM
+u7jjN+oUmOle6ZgPHGE1yQ3UgTFWaY92o2kwZzAdBgNVHQ4EFgQUXi+Z+IyIcpog
+lnuMEjEa4o/VDOwwHwYDVR0jBBgwFoAUXi+Z+IyIcpoglnuMEjEa4o/VDOwwDwYD
+VR0TAQH/BAUwAwEB/zAUBgNVHREEDTALgglhbGljZS5jb20wCgYIKoZIzj0EAwID
+RwAwRAIgZrAFaTHbWJGXtt1RtjJQrvFw2mYm7XyOWEKFtayCdMYCIBSr+bMUd3Qa
+DMJDKQLXEr5Aqsf4gdpJNXOqY8VXgR4N
+-----END CERTIFICATE-----";
+
+    pub fn test_certificate() -> PeerCertificate {
+        PeerCertificate(parse_certs(TEST_CERT).unwrap().remove(0))
+    }
+
+    #[test]
+    fn test_parse_certs() {
+        assert_eq!(parse_certs(TEST_CERT).unwrap().len(), 1);
+        assert!(parse_certs("").is_err());
+        assert!(parse_certs("not a certificate").is_err());
+    }
+
+    #[test]
+    fn test_parse_private_key() {
+        assert!(parse_private_key("").is_err());
+        assert!(parse_private_key(TEST_CERT).is_err());
+    }
+
+    #[test]
+    fn test_peer_certificate_is_valid_for() {
+        let cert = test_certificate();
+        assert!(cert.is_valid_for(&Name::from_str("alice.com").unwrap()));
+        assert!(cert.is_valid_for(&Name::from_str("alice.com.").unwrap()));
+        assert!(!cert.is_valid_for(&Name::from_str("bob.com").unwrap()));
+        assert!(!cert.is_valid_for(&Name::from_str("sub.alice.com").unwrap()));
+    }
+}
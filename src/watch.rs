@@ -0,0 +1,221 @@
+use std::{convert::Infallible, sync::LazyLock, time::Duration};
+
+use actix_web::web::Bytes;
+use futures_util::{stream, Stream, StreamExt};
+use log::{debug, warn};
+use serde::Serialize;
+use serde_json::to_string;
+use tokio::{
+    sync::broadcast::{self, error::RecvError, Receiver, Sender},
+    time::timeout,
+};
+
+use crate::registry::{Registry, Services};
+
+const CHANNEL_CAPACITY: usize = 256;
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+static CHANGES: LazyLock<Sender<Change>> = LazyLock::new(|| broadcast::channel(CHANNEL_CAPACITY).0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Visibility {
+    Public,
+    Private,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Change {
+    pub registry: String,
+    pub visibility: Visibility,
+    pub added: Services,
+    pub removed: Services,
+}
+
+fn diff(old: &Services, new: &Services) -> (Services, Services) {
+    let changed = |from: &Services, to: &Services| -> Services {
+        from.iter()
+            .filter(|(service, ports)| to.get(*service) != Some(ports))
+            .map(|(service, ports)| (service.clone(), ports.clone()))
+            .collect()
+    };
+
+    (changed(new, old), changed(old, new))
+}
+
+fn changes(old: &Registry, new: &Registry) -> Vec<Change> {
+    let hostname = new.hostname().to_string();
+    let bumped = (old.epoch(), old.generation()) != (new.epoch(), new.generation());
+
+    [
+        (
+            Visibility::Public,
+            old.public_services(),
+            new.public_services(),
+            bumped,
+        ),
+        (
+            Visibility::Private,
+            old.private_services(),
+            new.private_services(),
+            false,
+        ),
+    ]
+    .into_iter()
+    .filter_map(|(visibility, old, new, bumped)| {
+        let (added, removed) = diff(old, new);
+        if added.is_empty() && removed.is_empty() && !bumped {
+            return None;
+        }
+
+        Some(Change {
+            registry: hostname.clone(),
+            visibility,
+            added,
+            removed,
+        })
+    })
+    .collect()
+}
+
+fn cleared(registry: &Registry) -> Registry {
+    let mut registry = registry.clone();
+    registry.clear_public_services();
+    registry.clear_private_services();
+
+    registry
+}
+
+pub fn publish_changes(old: &Registry, new: &Registry) {
+    for change in changes(old, new) {
+        debug!("Publishing registry change: {:?}", change);
+        let _ = CHANGES.send(change);
+    }
+}
+
+pub fn publish_removed(registry: &Registry) {
+    publish_changes(registry, &cleared(registry));
+}
+
+pub fn subscribe_changes() -> Receiver<Change> {
+    CHANGES.subscribe()
+}
+
+pub async fn wait_for_change(
+    receiver: &mut Receiver<Change>,
+    registry: &str,
+    visibility: Visibility,
+) {
+    loop {
+        match receiver.recv().await {
+            Ok(change) if change.registry == registry && change.visibility == visibility => return,
+            Ok(_) => continue,
+            Err(RecvError::Lagged(_) | RecvError::Closed) => return,
+        }
+    }
+}
+
+fn event(change: &Change) -> Bytes {
+    Bytes::from(format!(
+        "event: change\ndata: {}\n\n",
+        to_string(change).unwrap()
+    ))
+}
+
+pub fn subscribe(
+    receiver: Receiver<Change>,
+    registries: Vec<Registry>,
+) -> impl Stream<Item = Result<Bytes, Infallible>> + 'static {
+    let snapshot: Vec<Bytes> = registries
+        .iter()
+        .flat_map(|registry| changes(&cleared(registry), registry))
+        .map(|change| event(&change))
+        .collect();
+
+    let updates = stream::unfold(receiver, |mut receiver: Receiver<Change>| async move {
+        let bytes = loop {
+            break match timeout(KEEP_ALIVE_INTERVAL, receiver.recv()).await {
+                Ok(Ok(change)) if change.added.is_empty() && change.removed.is_empty() => continue,
+                Ok(Ok(change)) => event(&change),
+                Ok(Err(RecvError::Lagged(skipped))) => {
+                    warn!("Watcher fell behind, skipped {} registry changes.", skipped);
+                    Bytes::from(format!("event: lagged\ndata: {}\n\n", skipped))
+                }
+                Ok(Err(RecvError::Closed)) => return None,
+                Err(_) => Bytes::from_static(b": keep-alive\n\n"),
+            };
+        };
+
+        Some((Ok(bytes), receiver))
+    });
+
+    stream::iter(snapshot.into_iter().map(Ok)).chain(updates)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn test_diff() {
+        let old = Services::from([
+            ("a".to_string(), BTreeSet::new()),
+            ("b".to_string(), BTreeSet::from([80])),
+            ("c".to_string(), BTreeSet::new()),
+        ]);
+        let new = Services::from([
+            ("a".to_string(), BTreeSet::new()),
+            ("b".to_string(), BTreeSet::from([8080])),
+            ("d".to_string(), BTreeSet::new()),
+        ]);
+
+        let (added, removed) = diff(&old, &new);
+        assert_eq!(
+            added,
+            Services::from([
+                ("b".to_string(), BTreeSet::from([8080])),
+                ("d".to_string(), BTreeSet::new()),
+            ])
+        );
+        assert_eq!(
+            removed,
+            Services::from([
+                ("b".to_string(), BTreeSet::from([80])),
+                ("c".to_string(), BTreeSet::new()),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_changes() {
+        let old: Registry = "http://bob.com:3000".parse().unwrap();
+        let mut new = old.clone();
+        new.add_public_service("app".to_string(), None);
+
+        assert_eq!(
+            changes(&old, &new),
+            vec![Change {
+                registry: "bob.com".to_string(),
+                visibility: Visibility::Public,
+                added: Services::from([("app".to_string(), BTreeSet::new())]),
+                removed: Services::new(),
+            }]
+        );
+        assert_eq!(changes(&new, &new), vec![]);
+        assert_eq!(changes(&new, &cleared(&new))[0].removed.len(), 1);
+
+        let mut bumped = new.clone();
+        bumped.start_epoch();
+        assert_eq!(
+            changes(&new, &bumped),
+            vec![Change {
+                registry: "bob.com".to_string(),
+                visibility: Visibility::Public,
+                added: Services::new(),
+                removed: Services::new(),
+            }]
+        );
+    }
+}